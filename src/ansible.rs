@@ -18,35 +18,151 @@
 //! Ansible interface
 
 
-use crate::exec;
-
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
-use std::io::Write;
 use faccess::PathExt;
 use yaml_rust::Yaml;
-use serde_json;
+use regex::Regex;
+use lazy_static::lazy_static;
 use serde_json::Value as Json;
-use tempfile::NamedTempFile;
+use chrono::DateTime;
+
+
+/// Parses a list of {name, value} environment variables, for the given command
+pub fn parse_env(yaml: &Yaml, command: &str) -> Result<HashMap<String, String>, String> {
+    match yaml {
+        Yaml::BadValue => Ok(HashMap::new()),
+        Yaml::Array(a) => a.iter().map(|i| Ok((
+            match &i["name"] {
+                Yaml::String(s) => String::from(s),
+                Yaml::BadValue => return Err("missing name property for environment variable".to_string()),
+                _ => return Err("non-string name property for environment variable".to_string())
+            },
+            match &i["value"] {
+                Yaml::String(s) => String::from(s),
+                Yaml::BadValue => return Err("missing value property for environment variable".to_string()),
+                _ => return Err("non-string value property for environment variable".to_string())
+            }))).collect::<Result<HashMap<String, String>, String>>(),
+        _ => Err(format!("expected list for the {} environment", command))
+    }
+}
+
+
+/// Converts YAML to JSON, for extra variables
+fn yaml_to_json(yaml: &Yaml) -> Result<Json, String> {
+    Ok(match yaml {
+        Yaml::String(s) => Json::from(s.as_str()),
+        Yaml::Integer(i) => Json::from(*i),
+        Yaml::Real(r) => Json::from(r.parse::<f64>().map_err(|e| format!("invalid number {}: {}", r, e))?),
+        Yaml::Boolean(b) => Json::from(*b),
+        Yaml::Null => Json::Null,
+        Yaml::Array(a) => Json::Array(a.iter().map(yaml_to_json).collect::<Result<Vec<Json>, String>>()?),
+        Yaml::Hash(h) => Json::Object(h.iter().map(|(k, v)| match k {
+            Yaml::String(k) => Ok((k.clone(), yaml_to_json(v)?)),
+            _ => Err("expected string keys in extra variables".to_string())
+        }).collect::<Result<serde_json::Map<String, Json>, String>>()?),
+        _ => return Err("unsupported value in extra variables".to_string())
+    })
+}
+
+
+/// Host variables written to the inventory by SetMeUp itself
+const RESERVED_HOST_VARS: [&str; 3] = ["ansible_host", "ansible_port", "ansible_user"];
+
+lazy_static! {
+    /// Valid host variable names, which make it to the inventory as they are
+    static ref HOST_VAR_NAME: Regex = Regex::new("^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
+}
 
+/// Extra variables given to ansible-playbook
+#[derive(Debug, PartialEq)]
+pub enum ExtraVars {
+    Inline(Json),
+    /// A variables file, relative to the source's root
+    File(String)
+}
 
 /// Parameters to use when invoking ansible-playbook
 pub struct AnsibleContext {
     pub path: Option<PathBuf>,
-    pub env: HashMap<String, String>
+    pub env: HashMap<String, String>,
+    pub extra_vars: Vec<ExtraVars>,
+    /// Inventory variables for the client, their values written as inventory literals
+    pub host_vars: BTreeMap<String, String>,
+    /// Appended to the ansible-playbook command line
+    pub args: Vec<String>
+}
+
+/// What playbooks are told about the session, as the smu extra variable
+pub struct SessionMetadata {
+    pub client_user: String,
+    pub reverse_port: u16,
+    pub source: String,
+    /// Commit checked out in the source, if it is a git repository
+    pub revision: Option<String>,
+    pub playbook: String,
+    /// Local user SetMeUp runs as
+    pub login: Option<String>,
+    /// Client address, from SSH_CLIENT
+    pub remote_ip: Option<String>
+}
+
+impl SessionMetadata {
+    /// Reads the login and remote address from the environment
+    pub fn new(client_user: String, reverse_port: u16, source: String, revision: Option<String>, playbook: String) -> Self {
+        Self {
+            client_user, reverse_port, source, revision, playbook,
+            login: std::env::var("USER").or_else(|_| std::env::var("LOGNAME")).ok(),
+            remote_ip: std::env::var("SSH_CLIENT").ok().and_then(|c| c.split_whitespace().next().map(String::from))
+        }
+    }
+
+    /// Extra variables passed to ansible-playbook, as a variables file whose strings Ansible must not template
+    pub fn to_yaml(&self) -> String {
+        /* JSON strings are valid double-quoted YAML scalars */
+        let unsafe_str = |s: &str| format!("!unsafe {}", Json::from(s));
+        let unsafe_opt = |s: &Option<String>| s.as_deref().map(unsafe_str).unwrap_or_else(|| "null".to_string());
+        format!(
+            "smu:\n  client_user: {}\n  reverse_port: {}\n  source: {}\n  revision: {}\n  playbook: {}\n  \
+             version: {}\n  login: {}\n  remote_ip: {}\n",
+            unsafe_str(&self.client_user), self.reverse_port, unsafe_str(&self.source), unsafe_opt(&self.revision),
+            unsafe_str(&self.playbook), unsafe_str(env!("CARGO_PKG_VERSION")), unsafe_opt(&self.login),
+            unsafe_opt(&self.remote_ip)
+        )
+    }
 }
 
 /// ansible-playbook task result
+#[derive(Default)]
 pub struct AnsibleTaskResult {
     pub name: String,
     pub success: bool,
     pub changed: bool,
-    pub message: String
+    pub message: String,
+    /// Time taken by the task, in seconds, when the callback reported it
+    pub duration: Option<f64>,
+    /// The module's full result for the host
+    pub details: Json
 }
 
 /// Collection of task results
 pub type AnsibleResult = Vec<AnsibleTaskResult>;
 
+/// A ready-to-run ansible-playbook invocation
+pub struct AnsibleCommand {
+    pub program: String,
+    pub args: Vec<String>,
+    pub env: HashMap<String, String>,
+    pub working_dir: PathBuf
+}
+
+/// Incremental reader for the events ansible-playbook prints with the ansible.posix.jsonl callback
+pub struct AnsibleEvents {
+    buffer: String,
+    pub results: AnsibleResult,
+    pub complete: bool
+}
+
 impl AnsibleContext {
     /// Handles parsing the path to ansible-playbook as well as the args and env we should use
     pub fn parse(yaml: &Yaml) -> Result<AnsibleContext, String> {
@@ -63,78 +179,211 @@ impl AnsibleContext {
                 _ => return Err("expected string for the ansible-playbook path".to_string())
             },
 
-            env: match &yaml["env"] {
-                Yaml::BadValue => HashMap::new(),
-                Yaml::Array(a) => a.iter().map(|i| Ok((
-                    match &i["name"] {
-                        Yaml::String(s) => String::from(s),
-                        Yaml::BadValue => return Err("missing name property for environment variable".to_string()),
-                        _ => return Err("non-string name property for environment variable".to_string())
-                    },
-                    match &i["value"] {
-                        Yaml::String(s) => String::from(s),
-                        Yaml::BadValue => return Err("missing value property for environment variable".to_string()),
-                        _ => return Err("non-string value property for environment variable".to_string())
-                    }))).collect::<Result<HashMap<String, String>, String>>()?,
-                _ => return Err("expected list for the ansible-playbook environment".to_string())
+            env: parse_env(&yaml["env"], "ansible-playbook")?,
+
+            extra_vars: match &yaml["extra_vars"] {
+                Yaml::BadValue => Vec::new(),
+                Yaml::Hash(_) => vec![ExtraVars::Inline(yaml_to_json(&yaml["extra_vars"])?)],
+                Yaml::Array(a) => a.iter().map(|i| match i {
+                    Yaml::String(s) => Ok(ExtraVars::File(s.clone())),
+                    Yaml::Hash(_) => Ok(ExtraVars::Inline(yaml_to_json(i)?)),
+                    _ => Err("expected file name or dictionary in the ansible-playbook extra_vars".to_string())
+                }).collect::<Result<Vec<ExtraVars>, String>>()?,
+                _ => return Err("expected dictionary or list for the ansible-playbook extra_vars".to_string())
+            },
+
+            host_vars: match &yaml["host_vars"] {
+                Yaml::BadValue => BTreeMap::new(),
+                Yaml::Hash(h) => h.iter().map(|(k, v)| Self::host_var(k, v)).collect::<Result<BTreeMap<String, String>, String>>()?,
+                _ => return Err("expected dictionary for the ansible-playbook host_vars".to_string())
+            },
+
+            args: match &yaml["args"] {
+                Yaml::BadValue => Vec::new(),
+                Yaml::Array(a) => a.iter().map(|i| match i {
+                    Yaml::String(s) => Ok(s.clone()),
+                    _ => Err("expected list of strings for the ansible-playbook args".to_string())
+                }).collect::<Result<Vec<String>, String>>()?,
+                _ => return Err("expected list of strings for the ansible-playbook args".to_string())
             }
         })
     }
 
-    /// Runs ansible-playbook for provisioning
-    pub fn execute(&self, key_path: &Path, inventory_path: &Path,
-                   playbook_path: &Path, source_dir_path: &Path) -> Result<AnsibleResult, String> {
-        let mut env = self.env.clone();
-        env.insert("ANSIBLE_CALLBACKS_ENABLED".into(), "ansible.posix.json".into());
-        env.insert("ANSIBLE_STDOUT_CALLBACK".into(), "ansible.posix.json".into());
-        env.insert("ANSIBLE_HOST_KEY_CHECKING".into(), "False".into());
+    /// Validates a host variable, rendering its value as an inventory literal
+    fn host_var(name: &Yaml, value: &Yaml) -> Result<(String, String), String> {
+        let name = match name {
+            Yaml::String(n) if HOST_VAR_NAME.is_match(n) => n.clone(),
+            _ => return Err(format!("invalid host variable name {:?}", name))
+        };
+
+        if RESERVED_HOST_VARS.contains(&name.as_str()) {
+            return Err(format!("host variable {} is set by SetMeUp", name));
+        }
+
+        let value = match value {
+            Yaml::String(s) => Json::from(s.as_str()).to_string(),
+            Yaml::Integer(i) => i.to_string(),
+            Yaml::Real(r) => r.clone(),
+            Yaml::Boolean(true) => "True".to_string(),
+            Yaml::Boolean(false) => "False".to_string(),
+            _ => return Err(format!("expected scalar value for host variable {}", name))
+        };
 
-        let playbook_fullpath = source_dir_path.join(playbook_path);
-        let mut play_file = NamedTempFile::new().map_err(|e| format!("failed to ready the temporary play: {}", e))?;
-        play_file.write(format!(
+        Ok((name, value))
+    }
+
+    /// Inventory line for the client, reached through the reverse port
+    pub fn inventory(&self, reverse_port: u16, username: &str) -> String {
+        let mut line = format!("provisionee ansible_host=127.0.0.1 ansible_port={} ansible_user={}",
+                               reverse_port, Json::from(username));
+        for (name, value) in &self.host_vars {
+            line.push_str(&format!(" {}={}", name, value));
+        }
+        line
+    }
+
+    /// Writes the temporary play importing the selected playbook
+    pub fn write_play(play_path: &Path, playbook_fullpath: &Path, cleanup_key: Option<&str>) -> Result<(), String> {
+        /* The trailing play is skipped if the client failed: the key's expiry-time covers that case */
+        std::fs::write(play_path, format!(
             concat!(
                 "- ansible.builtin.import_playbook: {}\n",
                 "- hosts: all\n",
                 "  gather_facts: no\n",
                 "  tasks:\n",
+                "{}",
                 "    - name: Closing connection\n",
                 "      ansible.builtin.meta: reset_connection\n"
             ),
-            playbook_fullpath.to_str().unwrap()
-        ).as_bytes()).map_err(|e| format!("failed to write the temporary play: {}", e))?;
+            playbook_fullpath.to_str().unwrap(),
+            match cleanup_key {
+                Some(k) => format!(
+                    concat!(
+                        "    - name: Removing the SetMeUp key\n",
+                        "      ansible.posix.authorized_key:\n",
+                        "        user: \"{{{{ ansible_user }}}}\"\n",
+                        "        key: \"{}\"\n",
+                        "        state: absent\n"
+                    ),
+                    k
+                ),
+                None => String::new()
+            }
+        )).map_err(|e| format!("failed to write the temporary play: {}", e))
+    }
+
+    /// Builds the ansible-playbook command line for provisioning
+    pub fn command(&self, key_path: Option<&Path>, inventory_path: &Path, session_path: &Path,
+                   play_path: &Path, source_dir_path: &Path) -> AnsibleCommand {
+        let mut env = self.env.clone();
+        env.insert("ANSIBLE_CALLBACKS_ENABLED".into(), "ansible.posix.jsonl".into());
+        env.insert("ANSIBLE_STDOUT_CALLBACK".into(), "ansible.posix.jsonl".into());
+        env.insert("ANSIBLE_HOST_KEY_CHECKING".into(), "False".into());
+
+        /* Without a key, ssh falls back to the agent found through SSH_AUTH_SOCK */
+        let mut args = Vec::new();
+        if let Some(k) = key_path {
+            args.extend(["--private-key".to_string(), k.to_str().unwrap().to_string()]);
+        }
+        args.extend(["-i".to_string(), inventory_path.to_str().unwrap().to_string()]);
+
+        for vars in &self.extra_vars {
+            args.extend(["--extra-vars".to_string(), match vars {
+                ExtraVars::Inline(v) => v.to_string(),
+                ExtraVars::File(f) => format!("@{}", f)
+            }]);
+        }
+
+        args.extend(self.args.iter().cloned());
 
-        let ansible_run = exec::run(
-            match &self.path {
-                Some(p) => p.as_path().to_str().unwrap(),
-                None => "ansible-playbook"
+        /* Given last, the session's variables cannot be overridden */
+        args.extend(["--extra-vars".to_string(), format!("@{}", session_path.to_str().unwrap())]);
+        args.push(play_path.to_str().unwrap().to_string());
+
+        AnsibleCommand {
+            program: match &self.path {
+                Some(p) => p.to_str().unwrap().to_string(),
+                None => "ansible-playbook".to_string()
             },
-            vec!(
-                "--private-key", key_path.to_str().unwrap(),
-                "-i", inventory_path.to_str().unwrap(),
-                play_file.path().to_str().unwrap()
-            ),
-            source_dir_path,
-            Some(&env),
-            true
-        );
-
-        let ansible_run = ansible_run?;
-        let ansible_json: Json = serde_json::from_str(&ansible_run).map_err(|e| e.to_string())?;
-        let plays = ansible_json["plays"].as_array().ok_or("missing plays array".to_string())?;
-        let tasks = plays.iter().map(|p| p["tasks"].as_array().unwrap()).flatten();
-        let results = tasks.map(|t| {
-            let task_result = &t["hosts"]["provisionee"];
-            let success = task_result["failed"].as_bool().unwrap_or(false);
-            let unreachable = task_result["unreachable"].as_bool().unwrap_or(false);
-            AnsibleTaskResult {
-                name: String::from(t["task"]["name"].as_str().unwrap_or("unnamed task")),
-                success: unreachable || !success,
-                changed: task_result["changed"].as_bool().unwrap_or(false),
-                message: String::from(task_result["msg"].as_str().unwrap_or("no details"))
+            args,
+            env,
+            working_dir: source_dir_path.to_path_buf()
+        }
+    }
+}
+
+impl AnsibleTaskResult {
+    /// Reads the start and end timestamps of a task
+    fn duration(duration: &Json) -> Option<f64> {
+        let timestamp = |k: &str| duration[k].as_str().and_then(|t| DateTime::parse_from_rfc3339(t).ok());
+        let elapsed = timestamp("end")? - timestamp("start")?;
+        elapsed.to_std().ok().map(|d| d.as_secs_f64())
+    }
+
+    /// Reads a task result from an ansible.posix.jsonl runner event
+    fn from_event(event: &Json) -> Option<Self> {
+        let task_result = &event["hosts"]["provisionee"];
+        match event["_event"].as_str() {
+            Some(e) if e.starts_with("v2_runner_on_") && task_result.is_object() => {
+                let failed = task_result["failed"].as_bool().unwrap_or(false);
+                let unreachable = task_result["unreachable"].as_bool().unwrap_or(false);
+                Some(Self {
+                    name: String::from(event["task"]["name"].as_str().unwrap_or("unnamed task")),
+                    success: !(failed || unreachable),
+                    changed: task_result["changed"].as_bool().unwrap_or(false),
+                    message: String::from(task_result["msg"].as_str().unwrap_or("no details")),
+                    duration: Self::duration(&event["task"]["duration"]),
+                    details: task_result.clone()
+                })
+            },
+            _ => None
+        }
+    }
+}
+
+impl AnsibleEvents {
+    pub fn new() -> Self {
+        Self { buffer: String::new(), results: Vec::new(), complete: false }
+    }
+
+    /// Consumes ansible-playbook output, which may stop in the middle of an event, and returns the number of new results
+    pub fn feed(&mut self, data: &str) -> usize {
+        self.buffer.push_str(data);
+        let before = self.results.len();
+
+        loop {
+            let mut stream = serde_json::Deserializer::from_str(&self.buffer).into_iter::<Json>();
+            let mut consumed = 0;
+            let mut garbage = false;
+
+            while let Some(event) = stream.next() {
+                match event {
+                    Ok(e) => {
+                        consumed = stream.byte_offset();
+                        if e["_event"].as_str() == Some("v2_playbook_on_stats") {
+                            self.complete = true;
+                        }
+                        else if let Some(r) = AnsibleTaskResult::from_event(&e) {
+                            self.results.push(r);
+                        }
+                    },
+                    Err(e) => {
+                        garbage = !e.is_eof();
+                        break;
+                    }
+                }
+            }
+
+            self.buffer.drain(..consumed);
+
+            /* Skip whatever non-JSON line ansible-playbook may have printed */
+            match (garbage, self.buffer.find('\n')) {
+                (true, Some(i)) => { self.buffer.drain(..=i); },
+                _ => break
             }
-        });
+        }
 
-        Ok(results.collect())
+        self.results.len() - before
     }
 }
 
@@ -143,7 +392,96 @@ impl Default for AnsibleContext {
     fn default() -> Self {
         Self {
             path: None,
-            env: HashMap::new()
+            env: HashMap::new(),
+            extra_vars: Vec::new(),
+            host_vars: BTreeMap::new(),
+            args: Vec::new()
+        }
+    }
+}
+
+
+#[cfg(test)]
+#[cfg(not(tarpaulin_include))]
+mod tests {
+    use super::*;
+
+    const EVENTS: &str = concat!(
+        r#"{"_event":"v2_playbook_on_play_start","play":{"name":"all"},"tasks":[]}"#, "\n",
+        r#"{"_event":"v2_playbook_on_task_start","task":{"name":"first"},"hosts":{}}"#, "\n",
+        "[WARNING]: something non-JSON\n",
+        r#"{"_event":"v2_runner_on_ok","task":{"name":"first","duration":{"start":"2021-06-01T10:00:00.000000Z","end":"2021-06-01T10:00:01.500000Z"}},"hosts":{"provisionee":{"changed":true,"rc":0}}}"#, "\n",
+        r#"{"_event":"v2_runner_on_failed","task":{"name":"second"},"hosts":{"provisionee":{"failed":true,"msg":"oops"}}}"#, "\n",
+        r#"{"_event":"v2_runner_on_unreachable","task":{"name":"third"},"hosts":{"provisionee":{"unreachable":true}}}"#, "\n",
+        r#"{"_event":"v2_playbook_on_stats","stats":{}}"#, "\n"
+    );
+
+    #[test]
+    fn events_parsed() -> Result<(), String> {
+        let mut events = AnsibleEvents::new();
+        events.feed(EVENTS);
+
+        if !events.complete {
+            return Err("missed the stats event".to_string())
+        }
+
+        let summary: Vec<(&str, bool, bool)> = events.results.iter().map(|r| (r.name.as_str(), r.success, r.changed)).collect();
+        if summary != vec![("first", true, true), ("second", false, false), ("third", false, false)] {
+            return Err(format!("unexpected results: {:?}", summary))
+        }
+
+        match (events.results[0].duration, events.results[0].details["rc"].as_i64(), events.results[1].duration) {
+            (Some(d), Some(0), None) if (d - 1.5).abs() < 1e-6 => Ok(()),
+            _ => Err("failed to keep task durations and details".to_string())
+        }
+    }
+
+    #[test]
+    fn session_extra_vars() -> Result<(), String> {
+        let session = SessionMetadata {
+            login: Some("setmeup".to_string()),
+            remote_ip: Some("192.0.2.1".to_string()),
+            ..SessionMetadata::new("{{ lookup('pipe', 'id') }}".to_string(), 2222, "dotfiles".to_string(), None,
+                                   "site.yml".to_string())
+        };
+        let context = AnsibleContext { args: vec!["-v".to_string()], ..AnsibleContext::default() };
+        let command = context.command(None, Path::new("inventory"), Path::new("session.yml"),
+                                      Path::new("play.yml"), Path::new("/tmp"));
+        if command.args[command.args.len() - 4..] != ["-v", "--extra-vars", "@session.yml", "play.yml"] {
+            return Err(format!("unexpected arguments: {:?}", command.args));
+        }
+
+        let yaml = session.to_yaml();
+        if !yaml.contains("client_user: !unsafe \"{{ lookup('pipe', 'id') }}\"") {
+            return Err(format!("client user not marked unsafe: {}", yaml));
+        }
+        let smu = &yaml_rust::YamlLoader::load_from_str(&yaml).map_err(|e| e.to_string())?[0]["smu"];
+        match (smu["client_user"].as_str(), smu["reverse_port"].as_i64(), smu["revision"].is_null(),
+               smu["remote_ip"].as_str(), smu["version"].as_str()) {
+            (Some("{{ lookup('pipe', 'id') }}"), Some(2222), true, Some("192.0.2.1"), Some(env!("CARGO_PKG_VERSION"))) => Ok(()),
+            _ => Err(format!("unexpected extra variables: {}", yaml))
+        }
+    }
+
+    #[test]
+    fn inventory_username_quoted() -> Result<(), String> {
+        match AnsibleContext::default().inventory(2222, "bob ansible_connection=local").as_str() {
+            "provisionee ansible_host=127.0.0.1 ansible_port=2222 ansible_user=\"bob ansible_connection=local\"" => Ok(()),
+            i => Err(format!("unexpected inventory: {}", i))
+        }
+    }
+
+    #[test]
+    fn events_split_anywhere() -> Result<(), String> {
+        let mut events = AnsibleEvents::new();
+        let mut count = 0;
+        for chunk in EVENTS.as_bytes().chunks(7) {
+            count += events.feed(std::str::from_utf8(chunk).unwrap());
+        }
+
+        match (count, events.complete, events.results[1].message.as_str()) {
+            (3, true, "oops") => Ok(()),
+            _ => Err(format!("got {} results out of split output", count))
         }
     }
 }
@@ -0,0 +1,85 @@
+/* Set Me Up, a minimalistic Ansible-based remote provisioning tool
+ * Copyright (C) 2021 Julien JPK (jjpk.me)
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. */
+
+
+//! Audit log of provisioning sessions
+
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::Utc;
+use lazy_static::lazy_static;
+
+
+lazy_static! {
+    static ref LOG_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+/// Sets the file audit entries should be appended to
+pub fn init(path: Option<PathBuf>) {
+    *LOG_PATH.lock().unwrap() = path;
+}
+
+/// The file audit entries are appended to, if any
+pub fn path() -> Option<PathBuf> {
+    LOG_PATH.lock().ok().and_then(|p| p.clone())
+}
+
+/// Appends an entry to the audit log, if any, ignoring failures
+pub fn log(message: &str) {
+    let path = match LOG_PATH.lock() {
+        Ok(p) => p.clone(),
+        Err(_) => return
+    };
+
+    if let Some(p) = path {
+        if let Ok(mut f) = OpenOptions::new().create(true).append(true).open(p) {
+            writeln!(f, "{} setmeup[{}]: {}",
+                     Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+                     std::process::id(),
+                     message).ok();
+        }
+    }
+}
+
+
+#[cfg(test)]
+#[cfg(not(tarpaulin_include))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_appends() -> Result<(), String> {
+        let dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let path = dir.path().join("audit.log");
+
+        init(Some(path.clone()));
+        log("first");
+        log("second");
+        init(None);
+        log("third");
+
+        let content = std::fs::read_to_string(&path).map_err(|e| e.to_string())?;
+        let lines: Vec<&str> = content.lines().collect();
+        match lines.len() == 2 && lines[0].ends_with(": first") && lines[1].ends_with(": second") {
+            true => Ok(()),
+            false => Err(format!("unexpected audit log content: {}", content))
+        }
+    }
+}
@@ -0,0 +1,191 @@
+/* Set Me Up, a minimalistic Ansible-based remote provisioning tool
+ * Copyright (C) 2021 Julien JPK (jjpk.me)
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. */
+
+
+//! Browsing state for provisioning results: filtering, cursor and task details
+
+
+use crate::ansible::{AnsibleResult, AnsibleTaskResult};
+use crate::menu::{self, Navigate};
+
+
+/// Which tasks are listed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResultFilter {
+    All,
+    Failed,
+    Changed
+}
+
+impl ResultFilter {
+    fn keeps(&self, task: &AnsibleTaskResult) -> bool {
+        match self {
+            Self::All => true,
+            Self::Failed => !task.success,
+            Self::Changed => task.changed
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::All => "all",
+            Self::Failed => "failed",
+            Self::Changed => "changed"
+        }
+    }
+}
+
+/// A filtered, scrollable list of task results
+pub struct ResultBrowser<'a> {
+    result: &'a AnsibleResult,
+    filter: ResultFilter,
+    visible: Vec<usize>,
+    cursor: usize,
+    offset: usize
+}
+
+impl<'a> ResultBrowser<'a> {
+    pub fn new(result: &'a AnsibleResult) -> Self {
+        Self { result, filter: ResultFilter::All, visible: (0..result.len()).collect(), cursor: 0, offset: 0 }
+    }
+
+    pub fn filter(&self) -> ResultFilter {
+        self.filter
+    }
+
+    pub fn set_filter(&mut self, filter: ResultFilter) {
+        self.filter = filter;
+        self.visible = self.result.iter().enumerate().filter(|(_, t)| filter.keeps(t)).map(|(i, _)| i).collect();
+        self.cursor = 0;
+        self.offset = 0;
+    }
+
+    /// Indices of the tasks kept by the filter
+    pub fn visible(&self) -> &[usize] {
+        &self.visible
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// The task under the cursor, if any
+    pub fn selection(&self) -> Option<&'a AnsibleTaskResult> {
+        self.visible.get(self.cursor).map(|i| &self.result[*i])
+    }
+
+    /// Scrolls so that the cursor is visible in a window of the given height, returning the first visible task
+    pub fn scroll(&mut self, height: usize) -> usize {
+        self.offset = menu::scrolled(self.cursor, self.offset, height);
+        self.offset
+    }
+}
+
+impl Navigate for ResultBrowser<'_> {
+    fn move_by(&mut self, delta: isize) {
+        self.cursor = menu::moved(self.cursor, delta, self.visible.len());
+    }
+
+    fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    fn end(&mut self) {
+        self.cursor = self.visible.len().saturating_sub(1);
+    }
+}
+
+
+/// Renders a number of seconds as something like 1h02m, 3m05s or 4.2s
+pub fn format_duration(seconds: f64) -> String {
+    let whole = seconds.round() as u64;
+    match whole {
+        0..=59 => format!("{:.1}s", seconds),
+        60..=3599 => format!("{}m{:02}s", whole / 60, whole % 60),
+        _ => format!("{}h{:02}m", whole / 3600, whole % 3600 / 60)
+    }
+}
+
+/// Counts of tasks by outcome and the time they took
+pub fn summary(result: &AnsibleResult) -> String {
+    let failed = result.iter().filter(|t| !t.success).count();
+    let mut summary = format!("{} tasks: {} ok, {} changed, {} failed",
+                              result.len(), result.len() - failed,
+                              result.iter().filter(|t| t.changed).count(), failed);
+
+    let durations: Vec<f64> = result.iter().filter_map(|t| t.duration).collect();
+    if !durations.is_empty() {
+        summary.push_str(&format!(" in {}", format_duration(durations.iter().sum())));
+    }
+    summary
+}
+
+/// The full result of a task, pretty-printed line by line
+pub fn details(task: &AnsibleTaskResult) -> Vec<String> {
+    serde_json::to_string_pretty(&task.details).unwrap_or_default()
+        .lines().map(String::from).collect()
+}
+
+
+#[cfg(test)]
+#[cfg(not(tarpaulin_include))]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn result() -> AnsibleResult {
+        vec![
+            AnsibleTaskResult { name: "first".to_string(), success: true, changed: true, duration: Some(61.0), ..Default::default() },
+            AnsibleTaskResult { name: "second".to_string(), success: true, duration: Some(4.0), ..Default::default() },
+            AnsibleTaskResult { name: "third".to_string(), details: json!({"rc": 1, "stderr_lines": ["oops"]}), ..Default::default() }
+        ]
+    }
+
+    #[test]
+    fn filtered() -> Result<(), String> {
+        let result = result();
+        let mut browser = ResultBrowser::new(&result);
+        browser.end();
+
+        browser.set_filter(ResultFilter::Failed);
+        if browser.visible() != [2] || browser.cursor() != 0 {
+            return Err(format!("unexpected failed tasks: {:?}", browser.visible()))
+        }
+
+        browser.set_filter(ResultFilter::Changed);
+        match browser.selection().map(|t| t.name.as_str()) {
+            Some("first") => Ok(()),
+            _ => Err(format!("unexpected changed tasks: {:?}", browser.visible()))
+        }
+    }
+
+    #[test]
+    fn summarised() -> Result<(), String> {
+        match summary(&result()).as_str() {
+            "3 tasks: 2 ok, 1 changed, 1 failed in 1m05s" => Ok(()),
+            s => Err(format!("unexpected summary: {}", s))
+        }
+    }
+
+    #[test]
+    fn detailed() -> Result<(), String> {
+        let lines = details(&result()[2]);
+        match lines.iter().any(|l| l.trim() == "\"oops\"") && lines.iter().any(|l| l.trim() == "\"rc\": 1,") {
+            true => Ok(()),
+            false => Err(format!("unexpected details: {:?}", lines))
+        }
+    }
+}
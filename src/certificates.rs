@@ -0,0 +1,137 @@
+/* Set Me Up, a minimalistic Ansible-based remote provisioning tool
+ * Copyright (C) 2021 Julien JPK (jjpk.me)
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. */
+
+
+//! SSH user certificates signed by a server-side CA
+
+
+use crate::exec;
+use crate::shutdown::TrackedPath;
+
+use std::path::PathBuf;
+use std::convert::TryFrom;
+
+use faccess::PathExt;
+use osshkeys::KeyPair;
+use yaml_rust::Yaml;
+
+
+/// An SSH user certificate authority, used to sign the generated keypairs
+pub struct CertificateAuthority {
+    pub key: PathBuf,
+    pub validity: u32
+}
+
+const DEFAULT_VALIDITY: u32 = 5;
+/// A year, in minutes
+const MAX_VALIDITY: u32 = 366 * 24 * 60;
+
+impl CertificateAuthority {
+    /// Parses the ssh_ca configuration block
+    pub fn parse(yaml: &Yaml) -> Result<Self, String> {
+        Ok(Self {
+            key: match &yaml["key"] {
+                Yaml::String(s) => {
+                    let path = PathBuf::from(s);
+                    match path.is_file() && path.readable() {
+                        true => path,
+                        false => return Err(format!("no readable SSH CA key at {}", path.to_str().unwrap()))
+                    }
+                },
+                Yaml::BadValue => return Err("missing key parameter for the SSH CA".to_string()),
+                _ => return Err("expected string for the SSH CA key".to_string())
+            },
+
+            validity: match &yaml["validity"] {
+                Yaml::BadValue => DEFAULT_VALIDITY,
+                v => v.as_i64().and_then(|i| u32::try_from(i).ok()).filter(|v| (1..=MAX_VALIDITY).contains(v))
+                    .ok_or_else(|| "expected positive integer for the SSH CA validity".to_string())?
+            }
+        })
+    }
+
+    /// Signs the public half of a keypair into a short-lived certificate for the given principal
+    pub fn sign(&self, keypair: &KeyPair, principal: &str) -> Result<String, String> {
+        let work_dir = tempfile::tempdir().map_err(|e| format!("failed to ready the signing directory: {}", e))?;
+        let _tracked = TrackedPath::new(work_dir.path());
+        let pubkey_path = work_dir.path().join("id.pub");
+        let cert_path = work_dir.path().join("id-cert.pub");
+
+        std::fs::write(
+            &pubkey_path,
+            keypair.serialize_publickey().map_err(|e| format!("failed to serialise keypair: {}", e))?
+        ).map_err(|e| format!("failed to write the public key to disk: {}", e))?;
+
+        exec::run(
+            "ssh-keygen",
+            vec!(
+                "-q",
+                "-s", self.key.to_str().unwrap(),
+                "-I", &format!("setmeup:{}", principal),
+                "-n", principal,
+                "-V", &format!("-1m:+{}m", self.validity),
+                "-O", "source-address=127.0.0.1,::1",
+                pubkey_path.to_str().unwrap()
+            ),
+            work_dir.path(),
+            None
+        ).map_err(|e| format!("failed to sign the public key: {}", e.trim_end()))?;
+
+        std::fs::read_to_string(&cert_path)
+            .map(|c| c.trim_end().to_string())
+            .map_err(|e| format!("failed to read the signed certificate: {}", e))
+    }
+}
+
+
+#[cfg(test)]
+#[cfg(not(tarpaulin_include))]
+mod tests {
+    use super::*;
+    use osshkeys::KeyType;
+    use std::path::Path;
+
+    fn generate_ca(dir: &Path) -> Result<CertificateAuthority, String> {
+        let key = dir.join("ca");
+        exec::run("ssh-keygen", vec!["-q", "-t", "ed25519", "-N", "", "-f", key.to_str().unwrap()],
+                  dir, None)?;
+        Ok(CertificateAuthority { key, validity: DEFAULT_VALIDITY })
+    }
+
+    #[test]
+    fn sign_ok() -> Result<(), String> {
+        let dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let ca = generate_ca(dir.path())?;
+        let keypair = KeyPair::generate(KeyType::ECDSA, 0).map_err(|e| e.to_string())?;
+
+        let cert = ca.sign(&keypair, "someone")?;
+        match cert.starts_with("ecdsa-sha2-nistp256-cert-v01@openssh.com ") {
+            true => Ok(()),
+            false => Err(format!("unexpected certificate: {}", cert))
+        }
+    }
+
+    #[test]
+    fn sign_non_existent_ca_ko() -> Result<(), String> {
+        let ca = CertificateAuthority { key: PathBuf::from("/nonexistent"), validity: DEFAULT_VALIDITY };
+        let keypair = KeyPair::generate(KeyType::ECDSA, 0).map_err(|e| e.to_string())?;
+
+        match ca.sign(&keypair, "someone") {
+            Ok(_) => Err("signed a key with a non-existent CA".to_string()),
+            Err(_) => Ok(())
+        }
+    }
+}
@@ -19,12 +19,17 @@
 
 
 use crate::sources::*;
+use crate::certificates::CertificateAuthority;
+use crate::keys::{KeySettings, AuthorizedKeyOptions};
+use crate::runs::RunSettings;
+use crate::reports::ReportSettings;
+use crate::discovery::DiscoverySettings;
+use crate::ui::UIMode;
 
 use std::path::{PathBuf, Path};
 
-use clap::ArgMatches;
 use directories::{ProjectDirs, BaseDirs, UserDirs};
-use yaml_rust::YamlLoader;
+use yaml_rust::{Yaml, YamlLoader};
 
 
 /// Returns a (ordered) vector of possible locations for the configuration file
@@ -37,22 +42,13 @@ fn get_default_locations() -> Vec<PathBuf> {
         },
 
         /* Proper per-app directory in XDG_CONFIG_DIR ? */
-        match ProjectDirs::from("me", "jjpk", "setmeup") {
-            Some(xdg_dirs) => Some([xdg_dirs.config_dir().to_str().unwrap(), "setmeup.yml"].iter().collect()),
-            None => None
-        },
+        ProjectDirs::from("me", "jjpk", "setmeup").map(|xdg_dirs| [xdg_dirs.config_dir().to_str().unwrap(), "setmeup.yml"].iter().collect()),
 
         /* Acceptable per-app file in XDG_CONFIG_DIR ? */
-        match BaseDirs::new() {
-            Some(xdg_dirs) => Some([xdg_dirs.config_dir().to_str().unwrap(), "setmeup.yml"].iter().collect()),
-            None => None
-        },
+        BaseDirs::new().map(|xdg_dirs| [xdg_dirs.config_dir().to_str().unwrap(), "setmeup.yml"].iter().collect()),
 
         /* Old-school file straight into the home directory? */
-        match UserDirs::new() {
-            Some(user_dirs) => Some([user_dirs.home_dir().to_str().unwrap(), ".setmeup.yml"].iter().collect()),
-            None => None
-        },
+        UserDirs::new().map(|user_dirs| [user_dirs.home_dir().to_str().unwrap(), ".setmeup.yml"].iter().collect()),
 
         /* System-wide configuration in an SMU directory? */
         Some(PathBuf::from("/etc/setmeup/setmeup.yml")),
@@ -60,14 +56,14 @@ fn get_default_locations() -> Vec<PathBuf> {
         /* System-wide configuration directly under /etc ? */
         Some(PathBuf::from("/etc/setmeup.yml"))
 
-    ].iter().flatten().map(|path| path.clone()).collect()
+    ].iter().flatten().cloned().collect()
 }
 
 /// Guesses the most appropriate location for the configuration file
-fn infer_configuration_path(args: ArgMatches) -> Result<PathBuf, ()> {
-    match args.value_of("config") {
+fn infer_configuration_path(explicit: Option<&str>) -> Result<PathBuf, ()> {
+    match explicit {
         Some(option_value) => Ok(PathBuf::from(option_value)),
-        None => match get_default_locations().iter().filter(|path| path.exists()).next() {
+        None => match get_default_locations().iter().find(|path| path.exists()) {
             Some(inferred_location) => Ok(inferred_location.clone()),
             None => Err(())
         }
@@ -77,13 +73,22 @@ fn infer_configuration_path(args: ArgMatches) -> Result<PathBuf, ()> {
 
 /// Set Me Up! configuration structure
 pub struct Config {
-    pub sources: Vec<Source>
+    pub sources: Vec<Source>,
+    pub certificate_authority: Option<CertificateAuthority>,
+    pub key: KeySettings,
+    pub authorized_keys: AuthorizedKeyOptions,
+    pub audit_log: Option<PathBuf>,
+    pub runs: RunSettings,
+    pub reports: ReportSettings,
+    pub ui: Option<UIMode>,
+    /// Lists the playbooks of every source in a single menu
+    pub all_playbooks: bool
 }
 
 impl Config {
-    /// Gets a path to the configuration file and forwards parsing
-    pub fn locate_and_parse(args: ArgMatches) -> Result<Self, String> {
-        match infer_configuration_path(args) {
+    /// Gets a path to the configuration file, explicitly given or not, and forwards parsing
+    pub fn locate_and_parse(explicit: Option<&str>) -> Result<Self, String> {
+        match infer_configuration_path(explicit) {
             Ok(path) => Self::parse(path.as_path()),
             Err(_) => Err("no configuration file found".to_string())
         }
@@ -105,13 +110,67 @@ impl Config {
             Err(e) => return Err(e.to_string())
         };
 
+        let discovery = match &yaml[0]["discovery"] {
+            Yaml::Hash(_) => DiscoverySettings::parse(&yaml[0]["discovery"])?,
+            Yaml::BadValue => DiscoverySettings::default(),
+            _ => return Err("expected dictionary for the discovery parameter".to_string())
+        };
+
         Ok(Self {
             sources: match yaml[0]["sources"].as_hash() {
                 Some(h) => h.iter().map(|(k, v)| Source::parse(String::from(match k.as_str() {
                     Some(s) => s,
                     None => return Err("expected string as source name".to_string())
-                }), &v)).collect::<Result<Vec<Source>, String>>()?,
+                }), v, &discovery)).collect::<Result<Vec<Source>, String>>()?,
                 None => return Err("missing or empty sources".to_string())
+            },
+
+            certificate_authority: match &yaml[0]["ssh_ca"] {
+                Yaml::Hash(_) => Some(CertificateAuthority::parse(&yaml[0]["ssh_ca"])?),
+                Yaml::BadValue => None,
+                _ => return Err("expected dictionary for the ssh_ca parameter".to_string())
+            },
+
+            key: match &yaml[0]["key"] {
+                Yaml::Hash(_) => KeySettings::parse(&yaml[0]["key"])?,
+                Yaml::BadValue => KeySettings::default(),
+                _ => return Err("expected dictionary for the key parameter".to_string())
+            },
+
+            authorized_keys: match &yaml[0]["authorized_keys"] {
+                Yaml::Hash(_) => AuthorizedKeyOptions::parse(&yaml[0]["authorized_keys"])?,
+                Yaml::BadValue => AuthorizedKeyOptions::default(),
+                _ => return Err("expected dictionary for the authorized_keys parameter".to_string())
+            },
+
+            audit_log: match &yaml[0]["audit_log"] {
+                Yaml::String(s) => Some(PathBuf::from(s)),
+                Yaml::BadValue => None,
+                _ => return Err("expected string for the audit_log parameter".to_string())
+            },
+
+            runs: match &yaml[0]["runs"] {
+                Yaml::Hash(_) => RunSettings::parse(&yaml[0]["runs"])?,
+                Yaml::BadValue => RunSettings::default(),
+                _ => return Err("expected dictionary for the runs parameter".to_string())
+            },
+
+            reports: match &yaml[0]["reports"] {
+                Yaml::Hash(_) => ReportSettings::parse(&yaml[0]["reports"])?,
+                Yaml::BadValue => ReportSettings::default(),
+                _ => return Err("expected dictionary for the reports parameter".to_string())
+            },
+
+            ui: match &yaml[0]["ui"] {
+                Yaml::String(s) => Some(UIMode::from_name(s)?),
+                Yaml::BadValue => None,
+                _ => return Err("expected string for the ui parameter".to_string())
+            },
+
+            all_playbooks: match &yaml[0]["all_playbooks"] {
+                Yaml::Boolean(b) => *b,
+                Yaml::BadValue => false,
+                _ => return Err("expected boolean for the all_playbooks parameter".to_string())
             }
         })
     }
@@ -120,9 +179,12 @@ impl Config {
 
 #[cfg(test)]
 #[cfg(not(tarpaulin_include))]
+#[allow(clippy::op_ref, clippy::needless_borrow, clippy::redundant_pattern_matching, clippy::len_zero)]
 mod tests {
     use super::*;
     use ctor::*;
+    use crate::reports::ReportFormat;
+    use crate::ansible::ExtraVars;
 
     #[ctor]
     fn init() {
@@ -130,8 +192,10 @@ mod tests {
         std::env::set_var("XDG_CONFIG_HOME", "/nonexistent");
     }
 
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
     use clap::{App, Arg};
+    use osshkeys::KeyType;
 
     fn get_test_yaml_file(name: &str) -> PathBuf {
         let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR unset");
@@ -160,7 +224,7 @@ mod tests {
         let matches = App::new("setmeup")
             .arg(Arg::new("config").short('c').takes_value(true))
             .get_matches_from(vec!["setmeup"]);
-        match Config::locate_and_parse(matches) {
+        match Config::locate_and_parse(matches.value_of("config")) {
             Ok(_) => Err("parsed configuration with no available configuration file".to_string()),
             Err(e) => match e.find("no configuration file found") {
                 Some(_) => Ok(()),
@@ -175,7 +239,7 @@ mod tests {
             .arg(Arg::new("config").short('c').takes_value(true))
             .get_matches_from(vec!["setmeup", "-c", get_test_yaml_file("located").to_str().unwrap()]);
 
-        match Config::locate_and_parse(matches) {
+        match Config::locate_and_parse(matches.value_of("config")) {
             Ok(c) => match c.sources[0].name.as_str() {
                 "located" => Ok(()),
                 _ => Err(format!("unexpected source name: {}", c.sources[0].name))
@@ -235,6 +299,21 @@ mod tests {
         expected_error_raised("non_boolean_recurse", "expected boolean for the recurse")
     }
 
+    #[test]
+    fn test_non_list_include_ko() -> Result<(), String> {
+        expected_error_raised("non_list_include", "expected list for the include")
+    }
+
+    #[test]
+    fn test_invalid_exclude_regex_ko() -> Result<(), String> {
+        expected_error_raised("invalid_exclude_regex", "invalid regex in the exclude")
+    }
+
+    #[test]
+    fn test_non_boolean_check_content_ko() -> Result<(), String> {
+        expected_error_raised("non_boolean_check_content", "expected boolean for the check_content")
+    }
+
     #[test]
     fn test_non_string_playbook_match_ko() -> Result<(), String> {
         expected_error_raised("non_string_playbook_match", "expected string for the playbook_match")
@@ -269,7 +348,7 @@ mod tests {
             return Err("matched a .txt file with the default REGEX".to_string())
         }
 
-        if let Some(_) = c.sources[0].pre_provision {
+        if let Some(_) = c.sources[0].hooks.pre_provision {
             return Err("unexpected pre_provision command".to_string())
         }
 
@@ -288,16 +367,84 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_non_boolean_all_playbooks_ko() -> Result<(), String> {
+        expected_error_raised("non_boolean_all_playbooks", "expected boolean for the all_playbooks")
+    }
+
+    #[test]
+    fn test_non_boolean_discovery_cache_ko() -> Result<(), String> {
+        expected_error_raised("non_boolean_discovery_cache", "expected boolean for the discovery cache")
+    }
+
+    #[test]
+    fn test_negative_pre_provision_interval_ko() -> Result<(), String> {
+        expected_error_raised("negative_pre_provision_interval", "expected non-negative integer for the pre_provision_interval")
+    }
+
+    #[test]
+    fn test_unknown_update_policy_ko() -> Result<(), String> {
+        expected_error_raised("unknown_update_policy", "unsupported update failure policy retry")
+    }
+
+    #[test]
+    fn test_unknown_snapshot_ko() -> Result<(), String> {
+        expected_error_raised("unknown_snapshot", "unsupported snapshot mode rsync")
+    }
+
+    #[test]
+    fn test_non_positive_max_depth_ko() -> Result<(), String> {
+        expected_error_raised("non_positive_max_depth", "expected positive integer for the max_depth")
+    }
+
+    #[test]
+    fn test_non_list_skip_dirs_ko() -> Result<(), String> {
+        expected_error_raised("non_list_skip_dirs", "expected list of strings for the skip_dirs")
+    }
+
+    #[test]
+    fn test_exploration_ok() -> Result<(), String> {
+        let c = expect_parse_ok("exploration")?;
+        let s = &c.sources[0];
+        match (s.recurse, s.max_depth, s.follow_links, s.skip_hidden, s.skip_dirs.as_slice()) {
+            (true, Some(3), true, true, [d]) if d == "roles" => Ok(()),
+            _ => Err("failed to parse the exploration parameters".to_string())
+        }
+    }
+
     #[test]
     fn test_non_string_pre_provision_ko() -> Result<(), String> {
         expected_error_raised("non_string_pre_provision", "expected string for the pre_provision")
     }
 
+    #[test]
+    fn test_non_dict_hooks_ko() -> Result<(), String> {
+        expected_error_raised("non_dict_hooks", "expected dictionary for the hooks")
+    }
+
+    #[test]
+    fn test_duplicate_pre_provision_ko() -> Result<(), String> {
+        expected_error_raised("duplicate_pre_provision", "pre_provision is set both as a source parameter and as a hook")
+    }
+
+    #[test]
+    fn test_hooks_ok() -> Result<(), String> {
+        let c = expect_parse_ok("hooks_ok")?;
+        let hooks = &c.sources[0].hooks;
+        match (&hooks.pre_provision, &hooks.post_provision, &hooks.on_success, &hooks.on_failure) {
+            (Some(pre), Some(post), None, Some(_))
+                if pre.command == "git pull" && post.cwd.as_deref() == Some(Path::new("scripts"))
+                    && post.timeout == Some(Duration::from_secs(30)) && post.stream
+                    && post.env.get("CHANNEL").map(String::as_str) == Some("provisioning") => Ok(()),
+            _ => Err("failed to parse the hooks".to_string())
+        }
+    }
+
     #[test]
     fn test_pre_provision_ok() -> Result<(), String> {
         let c = expect_parse_ok("pre_provision_ok")?;
-        match &c.sources[0].pre_provision {
-            Some(s) => if s == "/bin/true" {
+        match &c.sources[0].hooks.pre_provision {
+            Some(h) => if h.command == "/bin/true" {
                 Ok(())
             }
             else {
@@ -382,4 +529,206 @@ mod tests {
             _ => Err(format!("parsed {} environment variables instead of 1", c.sources[0].ansible.env.len()))
         }
     }
+
+    #[test]
+    fn test_ansible_playbook_invalid_extra_vars_ko() -> Result<(), String> {
+        expected_error_raised("ansible_playbook_invalid_extra_vars", "expected dictionary or list for the ansible-playbook extra_vars")
+    }
+
+    #[test]
+    fn test_ansible_playbook_non_dict_host_vars_ko() -> Result<(), String> {
+        expected_error_raised("ansible_playbook_non_dict_host_vars", "expected dictionary for the ansible-playbook host_vars")
+    }
+
+    #[test]
+    fn test_ansible_playbook_reserved_host_var_ko() -> Result<(), String> {
+        expected_error_raised("ansible_playbook_reserved_host_var", "host variable ansible_port is set by SetMeUp")
+    }
+
+    #[test]
+    fn test_ansible_playbook_non_scalar_host_var_ko() -> Result<(), String> {
+        expected_error_raised("ansible_playbook_non_scalar_host_var", "expected scalar value for host variable packages")
+    }
+
+    #[test]
+    fn test_ansible_playbook_non_list_args_ko() -> Result<(), String> {
+        expected_error_raised("ansible_playbook_non_list_args", "expected list of strings for the ansible-playbook args")
+    }
+
+    #[test]
+    fn test_ansible_playbook_non_string_arg_ko() -> Result<(), String> {
+        expected_error_raised("ansible_playbook_non_string_arg", "expected list of strings for the ansible-playbook args")
+    }
+
+    #[test]
+    fn test_ansible_playbook_extras_ok() -> Result<(), String> {
+        let c = expect_parse_ok("ansible_playbook_extras")?;
+        let ansible = &c.sources[0].ansible;
+
+        match ansible.extra_vars.as_slice() {
+            [ExtraVars::File(f), ExtraVars::Inline(v)] if f == "vars/common.yml" && v["packages"][1] == "vim" => (),
+            _ => return Err("failed to parse the ansible-playbook extra_vars".to_string())
+        }
+
+        match (ansible.inventory(2222, "alice").as_str(), ansible.args.len()) {
+            ("provisionee ansible_host=127.0.0.1 ansible_port=2222 ansible_user=\"alice\" ansible_become=True ansible_python_interpreter=\"/usr/bin/python3\"", 5) => Ok(()),
+            r => Err(format!("failed to parse the ansible-playbook host_vars and args: {:?}", r))
+        }
+    }
+
+    #[test]
+    fn test_default_key_ok() -> Result<(), String> {
+        let c = expect_parse_ok("local_ok")?;
+
+        if c.sources[0].key.is_some() {
+            return Err("stored per-source key settings as defaults".to_string())
+        }
+
+        match c.key.key_type {
+            KeyType::ECDSA => Ok(()),
+            _ => Err("default key type is not ECDSA".to_string())
+        }
+    }
+
+    #[test]
+    fn test_key_unsupported_type_ko() -> Result<(), String> {
+        expected_error_raised("key_unsupported_type", "unsupported key type")
+    }
+
+    #[test]
+    fn test_key_unsupported_size_ko() -> Result<(), String> {
+        expected_error_raised("key_unsupported_size", "unsupported size for ECDSA keys")
+    }
+
+    #[test]
+    fn test_non_dict_source_key_ko() -> Result<(), String> {
+        expected_error_raised("non_dict_source_key", "expected dictionary for the key source parameter")
+    }
+
+    #[test]
+    fn test_key_ok() -> Result<(), String> {
+        let c = expect_parse_ok("key_ok")?;
+
+        if c.key.key_type != KeyType::ED25519 {
+            return Err("failed to parse the global key type".to_string())
+        }
+
+        match c.sources[0].key {
+            Some(k) => match (k.key_type, k.size) {
+                (KeyType::RSA, 4096) => Ok(()),
+                _ => Err("parsed the wrong per-source key settings".to_string())
+            },
+            None => Err("failed to parse per-source key settings".to_string())
+        }
+    }
+
+    #[test]
+    fn test_authorized_keys_invalid_expiry_ko() -> Result<(), String> {
+        expected_error_raised("authorized_keys_invalid_expiry", "expected non-negative integer for the authorized_keys expiry")
+    }
+
+    #[test]
+    fn test_authorized_keys_too_long_expiry_ko() -> Result<(), String> {
+        expected_error_raised("authorized_keys_too_long_expiry", "expected non-negative integer for the authorized_keys expiry")
+    }
+
+    #[test]
+    fn test_authorized_keys_ok() -> Result<(), String> {
+        let c = expect_parse_ok("authorized_keys_ok")?;
+        match (c.authorized_keys.expiry, c.authorized_keys.cleanup) {
+            (0, false) => Ok(()),
+            _ => Err("failed to parse the authorized_keys options".to_string())
+        }
+    }
+
+    #[test]
+    fn test_non_string_audit_log_ko() -> Result<(), String> {
+        expected_error_raised("non_string_audit_log", "expected string for the audit_log parameter")
+    }
+
+    #[test]
+    fn test_runs_non_boolean_detach_ko() -> Result<(), String> {
+        expected_error_raised("runs_non_boolean_detach", "expected boolean for the runs detach parameter")
+    }
+
+    #[test]
+    fn test_runs_ok() -> Result<(), String> {
+        let c = expect_parse_ok("runs_ok")?;
+        match (c.runs.directory.to_str().unwrap(), c.runs.detach) {
+            ("/tmp/setmeup-runs", true) => Ok(()),
+            _ => Err("failed to parse the runs parameters".to_string())
+        }
+    }
+
+    #[test]
+    fn test_reports_unknown_format_ko() -> Result<(), String> {
+        expected_error_raised("reports_unknown_format", "unsupported report format")
+    }
+
+    #[test]
+    fn test_reports_ok() -> Result<(), String> {
+        match (expect_parse_ok("reports_ok")?.reports.stdout, expect_parse_ok("local_ok")?.reports.stdout) {
+            (Some(ReportFormat::JUnit), None) => Ok(()),
+            _ => Err("failed to parse the reports parameters".to_string())
+        }
+    }
+
+    #[test]
+    fn test_unknown_ui_ko() -> Result<(), String> {
+        expected_error_raised("ui_unknown", "unsupported UI mode")
+    }
+
+    #[test]
+    fn test_ui_ok() -> Result<(), String> {
+        match (expect_parse_ok("ui_ok")?.ui, expect_parse_ok("local_ok")?.ui) {
+            (Some(UIMode::Basic), None) => Ok(()),
+            _ => Err("failed to parse the ui parameter".to_string())
+        }
+    }
+
+    #[test]
+    fn test_no_ssh_ca_ok() -> Result<(), String> {
+        let c = expect_parse_ok("local_ok")?;
+        match c.certificate_authority {
+            Some(_) => Err("parsed an SSH CA although none was given".to_string()),
+            None => Ok(())
+        }
+    }
+
+    #[test]
+    fn test_non_dict_ssh_ca_ko() -> Result<(), String> {
+        expected_error_raised("non_dict_ssh_ca", "expected dictionary for the ssh_ca")
+    }
+
+    #[test]
+    fn test_ssh_ca_no_key_ko() -> Result<(), String> {
+        expected_error_raised("ssh_ca_no_key", "missing key parameter for the SSH CA")
+    }
+
+    #[test]
+    fn test_ssh_ca_non_existent_key_ko() -> Result<(), String> {
+        expected_error_raised("ssh_ca_non_existent_key", "no readable SSH CA key at")
+    }
+
+    #[test]
+    fn test_ssh_ca_invalid_validity_ko() -> Result<(), String> {
+        expected_error_raised("ssh_ca_invalid_validity", "expected positive integer for the SSH CA validity")
+    }
+
+    #[test]
+    fn test_ssh_ca_too_long_validity_ko() -> Result<(), String> {
+        expected_error_raised("ssh_ca_too_long_validity", "expected positive integer for the SSH CA validity")
+    }
+
+    #[test]
+    fn test_ssh_ca_ok() -> Result<(), String> {
+        let c = expect_parse_ok("ssh_ca_ok")?;
+        match c.certificate_authority {
+            Some(ca) => match ca.validity {
+                10 => Ok(()),
+                v => Err(format!("parsed the wrong SSH CA validity: {}", v))
+            },
+            None => Err("failed to parse the SSH CA".to_string())
+        }
+    }
 }
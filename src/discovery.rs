@@ -0,0 +1,274 @@
+/* Set Me Up, a minimalistic Ansible-based remote provisioning tool
+ * Copyright (C) 2021 Julien JPK (jjpk.me)
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. */
+
+
+//! On-disk cache of the playbooks found in each source
+
+
+use crate::playbooks::PlaybookInfo;
+
+use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use directories::ProjectDirs;
+use serde_json::{json, Value as Json};
+use yaml_rust::{Yaml, YamlEmitter};
+
+
+/// Whether discovery results are cached, and where they are kept along with source update states
+pub struct DiscoverySettings {
+    pub cache: bool,
+    pub directory: PathBuf
+}
+
+impl DiscoverySettings {
+    /// Parses the discovery configuration block
+    pub fn parse(yaml: &Yaml) -> Result<Self, String> {
+        let defaults = Self::default();
+        Ok(Self {
+            cache: match &yaml["cache"] {
+                Yaml::Boolean(b) => *b,
+                Yaml::BadValue => defaults.cache,
+                _ => return Err("expected boolean for the discovery cache parameter".to_string())
+            },
+
+            directory: match &yaml["directory"] {
+                Yaml::String(s) => PathBuf::from(s),
+                Yaml::BadValue => defaults.directory,
+                _ => return Err("expected string for the discovery directory".to_string())
+            }
+        })
+    }
+
+    /// Base name of the files kept for a source, safe whatever the source name
+    fn file_name(source_name: &str) -> String {
+        let name: String = source_name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect();
+        format!("{}-{:016x}", name, hash(source_name))
+    }
+
+    /// Cache file for a source, if caching is enabled
+    pub fn cache_file(&self, source_name: &str) -> Option<PathBuf> {
+        match self.cache {
+            true => Some(self.directory.join(format!("{}.json", Self::file_name(source_name)))),
+            false => None
+        }
+    }
+
+    /// State file for a source, locked while it is updated
+    pub fn state_file(&self, source_name: &str) -> PathBuf {
+        self.directory.join(format!("{}.state", Self::file_name(source_name)))
+    }
+}
+
+impl Default for DiscoverySettings {
+    /// Uncached discovery, update states being kept in the user's cache directory
+    fn default() -> Self {
+        Self {
+            cache: false,
+            directory: match ProjectDirs::from("me", "jjpk", "setmeup") {
+                Some(d) => d.cache_dir().join("sources"),
+                None => std::env::temp_dir().join("setmeup-sources")
+            }
+        }
+    }
+}
+
+
+fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Identifies a source's configuration, so that changing it invalidates the cache
+pub fn fingerprint(name: &str, yaml: &Yaml) -> String {
+    let mut dump = String::new();
+    let _ = YamlEmitter::new(&mut dump).dump(yaml);
+    format!("{}-{:016x}", env!("CARGO_PKG_VERSION"), hash(&(name, dump)))
+}
+
+/// Modification time of a file or directory, following links
+pub fn mtime(path: &Path) -> Option<String> {
+    let modified = std::fs::metadata(path).ok()?.modified().ok()?;
+    let since_epoch = modified.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!("{}.{:09}", since_epoch.as_secs(), since_epoch.subsec_nanos()))
+}
+
+/// Commit checked out in a git repository, read from .git without calling git
+pub fn git_head(root: &Path) -> Option<String> {
+    let git = root.join(".git");
+    let head = std::fs::read_to_string(git.join("HEAD")).ok()?;
+    let head = head.trim();
+
+    let reference = match head.strip_prefix("ref: ") {
+        Some(r) => r,
+        None => return Some(head.to_string())
+    };
+
+    match std::fs::read_to_string(git.join(reference)) {
+        Ok(commit) => Some(commit.trim().to_string()),
+        Err(_) => std::fs::read_to_string(git.join("packed-refs")).ok()?.lines()
+            .filter_map(|l| l.split_once(' '))
+            .find(|(_, r)| *r == reference)
+            .map(|(commit, _)| commit.to_string())
+    }
+}
+
+
+/// Playbooks found in a source, along with what they were found from
+#[derive(Debug, PartialEq)]
+pub struct DiscoveryCache {
+    pub fingerprint: String,
+    pub head: Option<String>,
+    /// Modification times of the explored directories and ignore file, by relative path
+    pub mtimes: BTreeMap<String, String>,
+    pub playbooks: Vec<PathBuf>,
+    /// What could be read from each playbook, in the same order
+    pub infos: Vec<PlaybookInfo>,
+    pub warnings: Vec<String>
+}
+
+impl DiscoveryCache {
+    /// Reads a cache file, None if it is missing or unreadable
+    pub fn load(file: &Path) -> Option<Self> {
+        let json: Json = serde_json::from_str(&std::fs::read_to_string(file).ok()?).ok()?;
+        let strings = |key: &str| json[key].as_array().map(|a| a.iter().filter_map(|s| s.as_str().map(String::from)).collect::<Vec<String>>());
+
+        /* The cache directory may be shared: whatever it holds must stay within the source */
+        let playbooks: Vec<PathBuf> = strings("playbooks")?.into_iter().map(PathBuf::from).collect();
+        if !playbooks.iter().all(|p| p.components().all(|c| matches!(c, Component::Normal(_)))) {
+            return None;
+        }
+
+        let infos = json["infos"].as_array()?.iter().map(PlaybookInfo::from_json).collect::<Option<Vec<PlaybookInfo>>>()?;
+        if infos.len() != playbooks.len() {
+            return None;
+        }
+
+        Some(Self {
+            fingerprint: json["fingerprint"].as_str()?.to_string(),
+            head: json["head"].as_str().map(String::from),
+            mtimes: json["mtimes"].as_object()?.iter()
+                .filter_map(|(p, m)| m.as_str().map(|m| (p.clone(), m.to_string())))
+                .collect(),
+            playbooks,
+            infos,
+            warnings: strings("warnings")?
+        })
+    }
+
+    /// Checks that nothing changed in the source since the cache was written
+    pub fn is_fresh(&self, root: &Path, fingerprint: &str) -> bool {
+        self.fingerprint == fingerprint
+            && self.head == git_head(root)
+            && self.mtimes.iter().all(|(p, m)| mtime(&root.join(p)).as_ref() == Some(m))
+    }
+
+    /// Writes the cache file, replacing any previous one at once
+    pub fn save(&self, file: &Path) -> Result<(), String> {
+        let directory = file.parent().unwrap_or_else(|| Path::new("."));
+        std::fs::create_dir_all(directory)
+            .map_err(|e| format!("failed to create the discovery cache directory: {}", e))?;
+
+        let json = json!({
+            "fingerprint": self.fingerprint,
+            "head": self.head,
+            "mtimes": self.mtimes,
+            "playbooks": self.playbooks.iter().map(|p| p.to_string_lossy()).collect::<Vec<_>>(),
+            "infos": self.infos.iter().map(PlaybookInfo::to_json).collect::<Vec<Json>>(),
+            "warnings": self.warnings
+        });
+
+        let mut temp = tempfile::NamedTempFile::new_in(directory)
+            .map_err(|e| format!("failed to write the discovery cache: {}", e))?;
+        temp.write_all(json.to_string().as_bytes())
+            .map_err(|e| format!("failed to write the discovery cache: {}", e))?;
+        temp.persist(file).map(|_| ())
+            .map_err(|e| format!("failed to write the discovery cache: {}", e))
+    }
+}
+
+
+#[cfg(test)]
+#[cfg(not(tarpaulin_include))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn git_head_read() -> Result<(), String> {
+        let root = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let git = root.path().join(".git");
+        std::fs::create_dir_all(git.join("refs/heads")).map_err(|e| e.to_string())?;
+
+        std::fs::write(git.join("HEAD"), "ref: refs/heads/main\n").map_err(|e| e.to_string())?;
+        std::fs::write(git.join("packed-refs"), "# pack-refs with: peeled\nabc123 refs/heads/main\n").map_err(|e| e.to_string())?;
+        let packed = git_head(root.path());
+
+        std::fs::write(git.join("refs/heads/main"), "def456\n").map_err(|e| e.to_string())?;
+        let loose = git_head(root.path());
+
+        match (packed.as_deref(), loose.as_deref(), git_head(&git)) {
+            (Some("abc123"), Some("def456"), None) => Ok(()),
+            r => Err(format!("unexpected heads: {:?}", r))
+        }
+    }
+
+    #[test]
+    fn cache_round_trip() -> Result<(), String> {
+        let root = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let cache_dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let file = cache_dir.path().join("cache/source.json");
+        let cache = DiscoveryCache {
+            fingerprint: "fingerprint".to_string(),
+            head: None,
+            mtimes: vec![(String::new(), mtime(root.path()).unwrap())].into_iter().collect(),
+            playbooks: vec![PathBuf::from("site.yml")],
+            infos: vec![PlaybookInfo { name: Some("Site".to_string()), tags: vec!["base".to_string()], ..Default::default() }],
+            warnings: vec!["skipped broken.yml".to_string()]
+        };
+        cache.save(&file)?;
+
+        match DiscoveryCache::load(&file) {
+            Some(c) if c == cache && c.is_fresh(root.path(), "fingerprint") && !c.is_fresh(root.path(), "other") => Ok(()),
+            c => Err(format!("unexpected cache read back: {:?}", c))
+        }
+    }
+
+    #[test]
+    fn cache_outside_source_ko() -> Result<(), String> {
+        let cache_dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let file = cache_dir.path().join("source.json");
+        for playbook in ["/etc/playbook.yml", "../playbook.yml"].iter() {
+            DiscoveryCache {
+                fingerprint: "fingerprint".to_string(),
+                head: None,
+                mtimes: BTreeMap::new(),
+                playbooks: vec![PathBuf::from(playbook)],
+                infos: vec![PlaybookInfo::default()],
+                warnings: Vec::new()
+            }.save(&file)?;
+
+            if DiscoveryCache::load(&file).is_some() {
+                return Err(format!("read back a cached playbook outside of the source: {}", playbook));
+            }
+        }
+        Ok(())
+    }
+}
@@ -18,15 +18,21 @@
 //! Utility functions to run external processes
 
 
+use crate::shutdown::TrackedChild;
+
 use std::path::Path;
 use std::collections::HashMap;
-use std::process::{Command, Stdio};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Child, Command, Stdio};
+use std::os::unix::process::CommandExt;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
 
 
-/// Executes the given program as an external process
+/// Executes the given program as an external process, in its own process group
 pub fn run(program: &str, args: Vec<&str>, working_dir: &Path,
-           env: Option<&HashMap<String, String>>,
-           is_ansible: bool) -> Result<String, String> {
+           env: Option<&HashMap<String, String>>) -> Result<String, String> {
     let mut command = Command::new(program);
     if let Some(e) = env {
         command.envs(e);
@@ -36,28 +42,105 @@ pub fn run(program: &str, args: Vec<&str>, working_dir: &Path,
         .current_dir(working_dir)
         .stdin(Stdio::null())
         .stdout(Stdio::piped())
-        .stderr(Stdio::piped());
+        .stderr(Stdio::piped())
+        .process_group(0);
+
+    let output = command.spawn().and_then(|child| {
+        let _tracked = TrackedChild::new(child.id());
+        child.wait_with_output()
+    });
 
-    match command.output() {
+    match output {
         Ok(o) => match o.status.success() {
             true => Ok(String::from_utf8_lossy(&o.stdout).to_string()),
-            false => match is_ansible {
-                true => {
-                    let output = String::from_utf8_lossy(&o.stdout).to_string();
-                    match output.starts_with("{") {
-                        true => Ok(output),
-                        false => Err(format!("{}\n{}", output, String::from_utf8_lossy(&o.stderr).to_string()))
-                    }
-                },
-                false => Err(format!("{}", String::from_utf8_lossy(&o.stderr).to_string()))
-            }
+            false => Err(format!("{}", String::from_utf8_lossy(&o.stderr)))
         },
         Err(e) => Err(format!("{}", e))
     }
 }
 
-/// Executes the given command-line through a shell in a new process
-pub fn shell(cmdline: &String, working_dir: &Path,
-             env: Option<&HashMap<String, String>>) -> Result<String, String> {
-    run("sh", vec!["-c", &cmdline], working_dir, env, false)
+/// Executes a command-line through a shell, handing each line of its output over as it comes
+///
+/// The command's process group is killed if it is still running after the timeout.
+pub fn shell_streamed<F: FnMut(&str)>(cmdline: &str, working_dir: &Path, env: &HashMap<String, String>,
+                                     timeout: Option<Duration>, mut on_line: F) -> Result<String, String> {
+    let mut child = Command::new("sh")
+        .args(["-c", cmdline])
+        .envs(env)
+        .current_dir(working_dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    let _tracked = TrackedChild::new(child.id());
+
+    /* Both pipes are read at once, lest the command block on a full one */
+    let (sender, receiver) = mpsc::channel();
+    let readers = vec![(false, child.stdout.take().map(|o| Box::new(o) as Box<dyn Read + Send>)),
+                   (true, child.stderr.take().map(|e| Box::new(e) as Box<dyn Read + Send>))];
+    for (is_stderr, pipe) in readers {
+        if let Some(pipe) = pipe {
+            let sender = sender.clone();
+            std::thread::spawn(move || {
+                for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+                    if sender.send((is_stderr, line)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+    drop(sender);
+
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let (mut stdout, mut stderr) = (String::new(), String::new());
+    loop {
+        let received = match deadline {
+            Some(d) => receiver.recv_timeout(d.saturating_duration_since(Instant::now())),
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        };
+
+        match received {
+            Ok((is_stderr, line)) => {
+                on_line(&line);
+                let output = if is_stderr { &mut stderr } else { &mut stdout };
+                output.push_str(&line);
+                output.push('\n');
+            },
+            Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {
+                unsafe { libc::killpg(child.id() as i32, libc::SIGKILL); }
+                child.wait().ok();
+                return Err(format!("{}timed out after {}s", stderr, timeout.unwrap_or_default().as_secs()));
+            }
+        }
+    }
+
+    match child.wait() {
+        Ok(status) if status.success() => Ok(stdout),
+        Ok(status) if stderr.trim().is_empty() => Err(format!("command failed with {}", status)),
+        Ok(_) => Err(stderr),
+        Err(e) => Err(e.to_string())
+    }
+}
+
+/// Starts the given program in its own process group, with its output redirected to files
+pub fn spawn(program: &str, args: &[String], working_dir: &Path,
+             env: Option<&HashMap<String, String>>,
+             stdout: &Path, stderr: &Path) -> Result<Child, String> {
+    let mut command = Command::new(program);
+    if let Some(e) = env {
+        command.envs(e);
+    }
+
+    command.args(args)
+        .current_dir(working_dir)
+        .stdin(Stdio::null())
+        .stdout(File::create(stdout).map_err(|e| format!("failed to create {}: {}", stdout.to_str().unwrap(), e))?)
+        .stderr(File::create(stderr).map_err(|e| format!("failed to create {}: {}", stderr.to_str().unwrap(), e))?)
+        .process_group(0)
+        .spawn()
+        .map_err(|e| format!("failed to run {}: {}", program, e))
 }
@@ -0,0 +1,286 @@
+/* Set Me Up, a minimalistic Ansible-based remote provisioning tool
+ * Copyright (C) 2021 Julien JPK (jjpk.me)
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. */
+
+
+//! Commands run around provisioning: before a source is used, and once a run is over
+
+
+use crate::ansible;
+use crate::exec;
+
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use serde_json::{json, Value as Json};
+use yaml_rust::Yaml;
+
+
+/// A command run at some point of a session, through a shell
+pub struct Hook {
+    pub command: String,
+    pub env: HashMap<String, String>,
+    /// Within the source's directory, which is the default
+    pub cwd: Option<PathBuf>,
+    pub timeout: Option<Duration>,
+    /// Shows the command's output to the client as it runs
+    pub stream: bool
+}
+
+impl Hook {
+    /// A command run from the source's directory with no timeout, its output kept from the client
+    pub fn new(command: String) -> Self {
+        Self { command, env: HashMap::new(), cwd: None, timeout: None, stream: false }
+    }
+
+    /// Parses a hook, given as a command-line or as a dictionary
+    pub fn parse(yaml: &Yaml, name: &str) -> Result<Self, String> {
+        match yaml {
+            Yaml::String(s) => return Ok(Self::new(s.clone())),
+            Yaml::Hash(_) => (),
+            _ => return Err(format!("expected string or dictionary for the {} hook", name))
+        }
+
+        Ok(Self {
+            command: match &yaml["command"] {
+                Yaml::String(s) => s.clone(),
+                Yaml::BadValue => return Err(format!("missing command for the {} hook", name)),
+                _ => return Err(format!("expected string for the {} hook command", name))
+            },
+            env: ansible::parse_env(&yaml["env"], &format!("{} hook", name))?,
+            cwd: match &yaml["cwd"] {
+                Yaml::String(s) if Path::new(s).components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) => Some(PathBuf::from(s)),
+                Yaml::String(_) => return Err(format!("expected relative path within the source for the {} hook cwd", name)),
+                Yaml::BadValue => None,
+                _ => return Err(format!("expected string for the {} hook cwd", name))
+            },
+            timeout: match yaml["timeout"] {
+                Yaml::Integer(i) if i > 0 => Some(Duration::from_secs(i as u64)),
+                Yaml::BadValue => None,
+                _ => return Err(format!("expected positive integer for the {} hook timeout", name))
+            },
+            stream: match yaml["stream"] {
+                Yaml::Boolean(b) => b,
+                Yaml::BadValue => false,
+                _ => return Err(format!("expected boolean for the {} hook stream parameter", name))
+            }
+        })
+    }
+
+    /// Runs the command from the given source directory, the session's variables added to its environment
+    ///
+    /// Lines of output are handed over as they come if the hook streams them.
+    pub fn run<F: FnMut(&str)>(&self, source_path: &Path, variables: &HashMap<String, String>, mut on_line: F) -> Result<String, String> {
+        let mut env = self.env.clone();
+        env.extend(variables.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+        let cwd = match &self.cwd {
+            Some(c) => source_path.join(c),
+            None => source_path.to_path_buf()
+        };
+
+        exec::shell_streamed(&self.command, &cwd, &env, self.timeout, |line| if self.stream {
+            on_line(line);
+        })
+    }
+
+    /// Stores the hook for whoever runs it once a run is over
+    fn to_json(&self) -> Json {
+        json!({
+            "command": self.command,
+            "env": self.env,
+            "cwd": self.cwd.as_ref().map(|c| c.to_string_lossy()),
+            "timeout": self.timeout.map(|t| t.as_secs()),
+            "stream": self.stream
+        })
+    }
+
+    /// Reads a stored hook back
+    fn from_json(json: &Json) -> Result<Self, String> {
+        Ok(Self {
+            command: json["command"].as_str().ok_or("invalid hook command")?.to_string(),
+            env: json["env"].as_object().ok_or("invalid hook environment")?.iter()
+                .map(|(k, v)| v.as_str().map(|v| (k.clone(), v.to_string())).ok_or("invalid hook environment"))
+                .collect::<Result<HashMap<String, String>, &str>>()?,
+            cwd: json["cwd"].as_str().map(PathBuf::from),
+            timeout: json["timeout"].as_u64().map(Duration::from_secs),
+            stream: json["stream"].as_bool().unwrap_or(false)
+        })
+    }
+}
+
+
+/// The hooks of a source
+#[derive(Default)]
+pub struct Hooks {
+    /// Updates the source before its playbooks are looked up
+    pub pre_provision: Option<Hook>,
+    /// Runs once a run is over, whatever its outcome
+    pub post_provision: Option<Hook>,
+    pub on_success: Option<Hook>,
+    pub on_failure: Option<Hook>
+}
+
+impl Hooks {
+    /// Parses the hooks source parameter
+    pub fn parse(yaml: &Yaml) -> Result<Self, String> {
+        match yaml {
+            Yaml::Hash(_) => (),
+            Yaml::BadValue => return Ok(Self::default()),
+            _ => return Err("expected dictionary for the hooks source parameter".to_string())
+        }
+
+        let hook = |name: &str| match &yaml[name] {
+            Yaml::BadValue => Ok(None),
+            h => Hook::parse(h, name).map(Some)
+        };
+
+        Ok(Self {
+            pre_provision: hook("pre_provision")?,
+            post_provision: hook("post_provision")?,
+            on_success: hook("on_success")?,
+            on_failure: hook("on_failure")?
+        })
+    }
+
+    /// Stores the hooks run once a run is over
+    pub fn to_json(&self) -> Json {
+        let hook = |h: &Option<Hook>| h.as_ref().map(Hook::to_json);
+        json!({
+            "post_provision": hook(&self.post_provision),
+            "on_success": hook(&self.on_success),
+            "on_failure": hook(&self.on_failure)
+        })
+    }
+
+    /// Reads stored hooks back, leaving pre_provision out
+    pub fn from_json(json: &Json) -> Result<Self, String> {
+        let hook = |name: &str| match &json[name] {
+            Json::Null => Ok(None),
+            h => Hook::from_json(h).map(Some)
+        };
+
+        Ok(Self {
+            pre_provision: None,
+            post_provision: hook("post_provision")?,
+            on_success: hook("on_success")?,
+            on_failure: hook("on_failure")?
+        })
+    }
+
+    /// Checks whether there is anything to run once a run is over
+    pub fn any_after_run(&self) -> bool {
+        self.post_provision.is_some() || self.on_success.is_some() || self.on_failure.is_some()
+    }
+
+    /// Hooks to run once a run is over, with their names
+    pub fn after_run(&self, success: bool) -> Vec<(&'static str, &Hook)> {
+        let outcome = match success {
+            true => ("on_success", &self.on_success),
+            false => ("on_failure", &self.on_failure)
+        };
+
+        vec![("post_provision", &self.post_provision), outcome].into_iter()
+            .filter_map(|(name, hook)| hook.as_ref().map(|h| (name, h)))
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+#[cfg(not(tarpaulin_include))]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn hooks(yaml: &str) -> Result<Hooks, String> {
+        Hooks::parse(&YamlLoader::load_from_str(yaml).unwrap()[0])
+    }
+
+    #[test]
+    fn run_with_variables() -> Result<(), String> {
+        let dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+        std::fs::create_dir(dir.path().join("scripts")).map_err(|e| e.to_string())?;
+
+        let hooks = hooks("{post_provision: {command: \"echo $GREETING $SMU_RESULT; pwd\", cwd: scripts, env: [{name: GREETING, value: hello}]}}")?;
+        let variables = vec![("SMU_RESULT".to_string(), "success".to_string())].into_iter().collect();
+        let output = hooks.post_provision.ok_or("missing post_provision hook")?.run(dir.path(), &variables, |_| ())?;
+
+        let lines = output.lines().collect::<Vec<&str>>();
+        match lines[..] {
+            ["hello success", cwd] if Path::new(cwd).ends_with("scripts") => Ok(()),
+            _ => Err(format!("unexpected hook output: {:?}", lines))
+        }
+    }
+
+    #[test]
+    fn timed_out() -> Result<(), String> {
+        let hook = Hook { timeout: Some(Duration::from_secs(1)), ..Hook::new("echo started >&2; sleep 30".to_string()) };
+        let started = std::time::Instant::now();
+
+        match (hook.run(&std::env::temp_dir(), &HashMap::new(), |_| ()), started.elapsed().as_secs()) {
+            (Err(e), s) if s < 10 && e.starts_with("started\n") && e.ends_with("timed out after 1s") => Ok(()),
+            r => Err(format!("unexpected timeout result: {:?}", r))
+        }
+    }
+
+    #[test]
+    fn silent_failure() -> Result<(), String> {
+        match Hook::new("exit 3".to_string()).run(&std::env::temp_dir(), &HashMap::new(), |_| ()) {
+            Err(e) if e == "command failed with exit status: 3" => Ok(()),
+            r => Err(format!("unexpected failure: {:?}", r))
+        }
+    }
+
+    #[test]
+    fn after_run() -> Result<(), String> {
+        let h = hooks("{post_provision: \"true\", on_failure: {command: \"true\", stream: true}}")?;
+        let names = |success| h.after_run(success).iter().map(|(n, _)| *n).collect::<Vec<&str>>();
+
+        match (names(true), names(false)) {
+            (s, f) if s == ["post_provision"] && f == ["post_provision", "on_failure"] => Ok(()),
+            r => Err(format!("unexpected hooks: {:?}", r))
+        }
+    }
+
+    #[test]
+    fn invalid_hooks() -> Result<(), String> {
+        match (hooks("[]"), hooks("{on_success: 42}"), hooks("{on_success: {cwd: /tmp}}"), hooks("{on_success: {command: x, timeout: 0}}")) {
+            (Err(_), Err(_), Err(_), Err(_)) => Ok(()),
+            _ => Err("accepted invalid hooks".to_string())
+        }
+    }
+
+    #[test]
+    fn cwd_outside_source() -> Result<(), String> {
+        match (hooks("{on_success: {command: x, cwd: /tmp}}"), hooks("{on_success: {command: x, cwd: scripts/../..}}")) {
+            (Err(a), Err(b)) if a == b && a == "expected relative path within the source for the on_success hook cwd" => Ok(()),
+            _ => Err("accepted a hook cwd outside of the source".to_string())
+        }
+    }
+
+    #[test]
+    fn stored() -> Result<(), String> {
+        let h = hooks("{pre_provision: \"true\", post_provision: {command: \"echo $A\", env: [{name: A, value: b}], cwd: scripts, timeout: 5, stream: true}}")?;
+        let stored = Hooks::from_json(&h.to_json())?;
+
+        match (stored.pre_provision, stored.post_provision, stored.on_success.is_some() || stored.on_failure.is_some()) {
+            (None, Some(p), false) if p.command == "echo $A" && p.env["A"] == "b" && p.cwd == Some(PathBuf::from("scripts"))
+                && p.timeout == Some(Duration::from_secs(5)) && p.stream => Ok(()),
+            _ => Err("failed to read stored hooks back".to_string())
+        }
+    }
+}
@@ -0,0 +1,223 @@
+/* Set Me Up, a minimalistic Ansible-based remote provisioning tool
+ * Copyright (C) 2021 Julien JPK (jjpk.me)
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. */
+
+
+//! Settings for the generated key pairs
+
+
+use osshkeys::{KeyPair, KeyType};
+use yaml_rust::Yaml;
+use chrono::{Duration, Utc};
+
+use std::convert::TryFrom;
+
+
+/// Algorithm and size of the key pairs SetMeUp generates
+#[derive(Clone, Copy)]
+pub struct KeySettings {
+    pub key_type: KeyType,
+    pub size: usize
+}
+
+impl KeySettings {
+    /// Parses a key configuration block
+    pub fn parse(yaml: &Yaml) -> Result<Self, String> {
+        let key_type = match &yaml["type"] {
+            Yaml::String(s) => match s.to_lowercase().as_str() {
+                "ecdsa" => KeyType::ECDSA,
+                "ed25519" => KeyType::ED25519,
+                "rsa" => KeyType::RSA,
+                _ => return Err(format!("unsupported key type: {}", s))
+            },
+            Yaml::BadValue => KeyType::ECDSA,
+            _ => return Err("expected string for the key type".to_string())
+        };
+
+        let size = match &yaml["size"] {
+            Yaml::Integer(i) if *i > 0 => *i as usize,
+            Yaml::BadValue => 0,
+            _ => return Err("expected positive integer for the key size".to_string())
+        };
+
+        let size_ok = match key_type {
+            KeyType::ECDSA => [0, 256, 384, 521].contains(&size),
+            KeyType::ED25519 => [0, 256].contains(&size),
+            KeyType::RSA => size == 0 || (2048..=16384).contains(&size),
+            KeyType::DSA => false
+        };
+
+        match size_ok {
+            true => Ok(Self { key_type, size }),
+            false => Err(format!("unsupported size for {} keys: {}", Self::type_name(key_type), size))
+        }
+    }
+
+    /// Human-readable name of a key type
+    fn type_name(key_type: KeyType) -> &'static str {
+        match key_type {
+            KeyType::ECDSA => "ECDSA",
+            KeyType::ED25519 => "Ed25519",
+            KeyType::RSA => "RSA",
+            KeyType::DSA => "DSA"
+        }
+    }
+
+    /// Actual size of the generated keys, resolving defaults
+    pub fn effective_size(&self) -> usize {
+        match (self.size, self.key_type) {
+            (0, KeyType::RSA) => 2048,
+            (0, KeyType::DSA) => 1024,
+            (0, _) => 256,
+            (s, _) => s
+        }
+    }
+
+    /// Describes the algorithm in use, as shown to the client
+    pub fn algorithm(&self) -> String {
+        match self.key_type {
+            KeyType::ED25519 => Self::type_name(self.key_type).to_string(),
+            _ => format!("{} ({} bits)", Self::type_name(self.key_type), self.effective_size())
+        }
+    }
+
+    /// Generates a key pair with these settings
+    pub fn generate(&self) -> Result<KeyPair, String> {
+        KeyPair::generate(self.key_type, self.size).map_err(|e| format!("failed to generate keypair: {}", e))
+    }
+}
+
+impl Default for KeySettings {
+    /// ECDSA, as SetMeUp always used
+    fn default() -> Self {
+        Self {
+            key_type: KeyType::ECDSA,
+            size: 0
+        }
+    }
+}
+
+/// Restrictions and lifetime of the authorized_keys entries clients are asked to install
+pub struct AuthorizedKeyOptions {
+    pub expiry: u32,
+    pub cleanup: bool
+}
+
+const DEFAULT_EXPIRY: u32 = 60;
+/// A year, in minutes
+const MAX_EXPIRY: u32 = 366 * 24 * 60;
+const ENTRY_RESTRICTIONS: &str = r#"from="127.0.0.1,::1",no-agent-forwarding,no-X11-forwarding"#;
+
+impl AuthorizedKeyOptions {
+    /// Parses the authorized_keys configuration block
+    pub fn parse(yaml: &Yaml) -> Result<Self, String> {
+        Ok(Self {
+            expiry: match &yaml["expiry"] {
+                Yaml::BadValue => DEFAULT_EXPIRY,
+                e => e.as_i64().and_then(|i| u32::try_from(i).ok()).filter(|e| *e <= MAX_EXPIRY)
+                    .ok_or_else(|| "expected non-negative integer for the authorized_keys expiry".to_string())?
+            },
+
+            cleanup: match &yaml["cleanup"] {
+                Yaml::Boolean(b) => *b,
+                Yaml::BadValue => true,
+                _ => return Err("expected boolean for the authorized_keys cleanup".to_string())
+            }
+        })
+    }
+
+    /// Renders the authorized_keys line for a public key, restricted to the reverse tunnel
+    pub fn entry(&self, pubkey: &str) -> String {
+        match self.expiry {
+            0 => format!("{} {}", ENTRY_RESTRICTIONS, pubkey),
+            e => format!(r#"{},expiry-time="{}" {}"#,
+                         ENTRY_RESTRICTIONS,
+                         (Utc::now() + Duration::minutes(e as i64)).format("%Y%m%d%H%MZ"),
+                         pubkey)
+        }
+    }
+
+    /// Renders a shell one-liner appending the entry to the client's authorized_keys file
+    pub fn snippet(&self, pubkey: &str) -> String {
+        format!("umask 077 && mkdir -p ~/.ssh && echo '{}' >> ~/.ssh/authorized_keys", self.entry(pubkey))
+    }
+}
+
+impl Default for AuthorizedKeyOptions {
+    /// One-hour entries, removed after provisioning
+    fn default() -> Self {
+        Self {
+            expiry: DEFAULT_EXPIRY,
+            cleanup: true
+        }
+    }
+}
+
+
+#[cfg(test)]
+#[cfg(not(tarpaulin_include))]
+mod tests {
+    use super::*;
+
+    fn expect_generated(settings: KeySettings) -> Result<(), String> {
+        let keypair = settings.generate()?;
+        match keypair.keytype() == settings.key_type {
+            true => Ok(()),
+            false => Err(format!("generated the wrong key type for {}", settings.algorithm()))
+        }
+    }
+
+    #[test]
+    fn generate_ecdsa() -> Result<(), String> {
+        expect_generated(KeySettings { key_type: KeyType::ECDSA, size: 384 })
+    }
+
+    #[test]
+    fn generate_ed25519() -> Result<(), String> {
+        expect_generated(KeySettings { key_type: KeyType::ED25519, size: 0 })
+    }
+
+    #[test]
+    fn generate_rsa() -> Result<(), String> {
+        expect_generated(KeySettings { key_type: KeyType::RSA, size: 2048 })
+    }
+
+    #[test]
+    fn entry_without_expiry() -> Result<(), String> {
+        let options = AuthorizedKeyOptions { expiry: 0, cleanup: true };
+        match options.entry("ssh-ed25519 AAAA").as_str() {
+            r#"from="127.0.0.1,::1",no-agent-forwarding,no-X11-forwarding ssh-ed25519 AAAA"# => Ok(()),
+            e => Err(format!("unexpected authorized_keys entry: {}", e))
+        }
+    }
+
+    #[test]
+    fn entry_with_expiry() -> Result<(), String> {
+        let entry = AuthorizedKeyOptions::default().entry("ssh-ed25519 AAAA");
+        match regex::Regex::new(r#",expiry-time="\d{12}Z" ssh-ed25519 AAAA$"#).unwrap().is_match(&entry) {
+            true => Ok(()),
+            false => Err(format!("unexpected authorized_keys entry: {}", entry))
+        }
+    }
+
+    #[test]
+    fn algorithm_default_size() -> Result<(), String> {
+        let settings = KeySettings { key_type: KeyType::RSA, size: 0 };
+        match settings.algorithm().as_str() {
+            "RSA (2048 bits)" => Ok(()),
+            a => Err(format!("unexpected algorithm description: {}", a))
+        }
+    }
+}
@@ -21,19 +21,108 @@
 mod provision;
 mod ansible;
 mod sources;
+mod playbooks;
+mod patterns;
+mod discovery;
+mod snapshot;
+mod updates;
+mod hooks;
 mod config;
 mod setup;
+mod session;
+mod certificates;
+mod keys;
+mod audit;
+mod shutdown;
+mod runs;
+mod reports;
+mod menu;
+mod browser;
 mod exec;
 mod ui;
 
 use config::Config;
-use setup::Setup;
-use provision::Provision;
-use ui::UI;
+use session::Session;
+use ui::{UI, UIMode};
+use runs::Run;
+use ansible::AnsibleResult;
+
+use std::path::Path;
 
 use clap::{Arg, App};
 
 
+/// Extracts the client's command: given through SSH_ORIGINAL_COMMAND with ForceCommand, or -c as a login shell
+///
+/// The flag is set when the command came from -c, which then holds no configuration file.
+fn client_command(config_option: Option<&str>) -> Option<(String, bool)> {
+    match std::env::var("SSH_ORIGINAL_COMMAND") {
+        Ok(c) if !c.trim().is_empty() => Some((c, false)),
+        _ => config_option.filter(|c| ClientCommand::is_command(c) && !Path::new(c).exists()).map(|c| (c.to_string(), true))
+    }
+}
+
+/// What the client asked for through its SSH command: a run to attach to and the interface to speak
+#[derive(Debug, Default, PartialEq)]
+struct ClientCommand {
+    attach: Option<String>,
+    ui: Option<UIMode>
+}
+
+impl ClientCommand {
+    /// Whether some text starts with one of the client commands
+    fn is_command(command: &str) -> bool {
+        matches!(command.split_whitespace().next(), Some("attach" | "--attach" | "ui" | "--ui"))
+    }
+
+    /// Parses `attach <run id>` and `ui <mode>` (also `--attach` and `--ui`), in any order
+    fn parse(command: &str) -> Result<Self, String> {
+        let mut parsed = Self::default();
+        let mut words = command.split_whitespace();
+        while let Some(word) = words.next() {
+            let (name, value) = match word.split_once('=') {
+                Some((n, v)) if n.starts_with("--") => (n, Some(v.to_string())),
+                _ => (word, None)
+            };
+            let value = value.or_else(|| words.next().map(String::from)).ok_or(format!("missing value for {}", name));
+            match name {
+                "attach" | "--attach" => parsed.attach = Some(value?),
+                "ui" | "--ui" => parsed.ui = Some(UIMode::from_name(&value?)?),
+                _ => return Err(format!("unsupported command {} (expected attach <run id> or ui <mode>)", word))
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// Hands the configured report over to the UI once a run is over
+fn report(config: &Config, run: &Run, result: &AnsibleResult) {
+    if let Some(format) = config.reports.stdout {
+        UI.report(format.name(), &format.render(result, &run.id));
+    }
+}
+
+/// Follows a run until it is over, then the hooks its supervisor runs afterwards
+#[cfg(not(tarpaulin_include))]
+fn follow(config: &Config, run: &Run) {
+    let result = run.follow(|i, task| UI.progress(i, task));
+    if let Ok(r) = &result {
+        audit::log(&format!("provisioning complete: {} tasks, {} failed",
+                            r.len(), r.iter().filter(|t| !t.success).count()));
+        UI.render_ansible_result(r);
+        report(config, run, r);
+    }
+
+    for (hook, error) in run.follow_hooks(|hook, line| UI.hook_output(hook, line)) {
+        UI.warning(&format!("The {} hook failed:\n{}", hook, error));
+    }
+    UI.flush_messages();
+
+    if let Err(e) = result {
+        UI.exit_with_error(&format!("Provisioning error: {}", e))
+    }
+}
+
 /// Set Me Up! entry point
 #[cfg(not(tarpaulin_include))]
 fn main() {
@@ -41,35 +130,128 @@ fn main() {
         .version("0.3.0")
         .about("Minimalistic Ansible-based remote provisioning tool")
         .arg(Arg::new("config").short('c').value_name("FILE").takes_value(true))
+        .arg(Arg::new("attach").long("attach").value_name("RUN_ID").takes_value(true)
+             .help("Follows a previous run again"))
+        .arg(Arg::new("ui").long("ui").value_name("MODE").takes_value(true).possible_values(["basic", "tty", "json"])
+             .help("Selects the interface instead of detecting it"))
+        .arg(Arg::new("supervise").long("supervise").value_name("RUN_DIR").takes_value(true).hide(true))
         .get_matches();
 
+    /* Detached supervisor for a run started by another session */
+    if let Some(dir) = options.value_of("supervise") {
+        std::process::exit(Run::supervise(Path::new(dir)));
+    }
+
+    /* The client knows best which interface it speaks */
+    let command = client_command(options.value_of("config"));
+    let requested = match &command {
+        Some((c, _)) => ClientCommand::parse(c),
+        None => Ok(ClientCommand::default())
+    };
+    let ui_option = match &requested {
+        Ok(ClientCommand { ui: Some(mode), .. }) => Some(*mode),
+        _ => options.value_of("ui").map(|m| UIMode::from_name(m).unwrap())
+    };
+    if let Some(mode) = ui_option {
+        ui::select(mode);
+    }
+
+    let attach = match requested {
+        Ok(r) => options.value_of("attach").map(String::from).or(r.attach),
+        Err(e) => UI.exit_with_error(&format!("Invalid command: {}", e))
+    };
+
     /* Locate, parse and validate the configuration file */
-    let run_config = match Config::locate_and_parse(options) {
+    let config_path = match command {
+        Some((_, true)) if options.value_of("attach").is_none() => None,
+        _ => options.value_of("config")
+    };
+    let run_config = match Config::locate_and_parse(config_path) {
         Ok(c) => c,
         Err(e) => UI.exit_with_error(&format!("Failed to parse configuration: {}", e))
     };
 
+    if let (None, Some(mode)) = (ui_option, run_config.ui) {
+        ui::select(mode);
+    }
+
+    audit::init(run_config.audit_log.clone());
+    audit::log(&format!("session started from {}", std::env::var("SSH_CLIENT").unwrap_or_else(|_| "unknown".to_string())));
+
+    if let Err(e) = shutdown::install() {
+        UI.exit_with_error(&format!("Failed to initialise: {}", e))
+    }
+
     UI.intro();
 
-    /* Prompt the user about the port, username and key */
-    let client_config = match Setup::prompt() {
-        Ok(s) => s,
-        Err(e) => UI.exit_with_error(&format!("Failed to set up the exchange: {}", e))
-    };
+    /* Follow a run started earlier */
+    if let Some(id) = attach {
+        let run = match Run::open(&run_config.runs, &id) {
+            Ok(r) => r,
+            Err(e) => UI.exit_with_error(&format!("Failed to attach: {}", e))
+        };
 
-    UI.next_step();
+        audit::log(&format!("attached to run {}", run.id));
+        UI.running();
+        follow(&run_config, &run);
+        return;
+    }
 
-    /* Prepare and execute provisioning */
-    let provisioner = match Provision::prompt(&run_config, &client_config) {
-        Ok(p) => p,
+    /* Prompt the user about the source, playbook, port, username and key */
+    let session = match Session::prompt(&run_config) {
+        Ok(s) => s,
         Err(e) => UI.exit_with_error(&format!("Failed to prepare for provisioning: {}", e))
     };
+    let (provisioner, client_config) = (session.provision, session.setup);
 
     UI.next_step();
-    UI.running();
 
-    match provisioner.execute() {
-        Ok(r) => UI.render_ansible_result(&r),
+    audit::log(&format!("provisioning {}/{} as {} through port {}",
+                        provisioner.source.name,
+                        provisioner.playbook_path.to_str().unwrap(),
+                        client_config.credentials.username,
+                        client_config.reverse_port));
+
+    match provisioner.execute(&client_config, &run_config.runs) {
+        Ok(run) => follow(&run_config, &run),
         Err(e) => UI.exit_with_error(&format!("Provisioning error: {}", e))
     }
 }
+
+
+#[cfg(test)]
+#[cfg(not(tarpaulin_include))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_commands() -> Result<(), String> {
+        let cases = [
+            ("attach 20261018-162941-9f3c6e1d2a4b7c80", ClientCommand { attach: Some("20261018-162941-9f3c6e1d2a4b7c80".to_string()), ui: None }),
+            ("ui json", ClientCommand { attach: None, ui: Some(UIMode::Json) }),
+            ("--ui=json attach abc", ClientCommand { attach: Some("abc".to_string()), ui: Some(UIMode::Json) }),
+            ("--attach abc --ui basic", ClientCommand { attach: Some("abc".to_string()), ui: Some(UIMode::Basic) })
+        ];
+
+        for (command, expected) in cases.iter() {
+            if ClientCommand::parse(command).as_ref() != Ok(expected) {
+                return Err(format!("failed to parse {:?}", command))
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn client_commands_ko() -> Result<(), String> {
+        for command in ["ls -la", "ui", "ui fancy", "attach"].iter() {
+            if ClientCommand::parse(command).is_ok() {
+                return Err(format!("accepted {:?}", command))
+            }
+        }
+
+        match (ClientCommand::is_command("ui json"), ClientCommand::is_command("/etc/setmeup.yml")) {
+            (true, false) => Ok(()),
+            _ => Err("failed to tell commands from configuration files".to_string())
+        }
+    }
+}
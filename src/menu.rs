@@ -0,0 +1,477 @@
+/* Set Me Up, a minimalistic Ansible-based remote provisioning tool
+ * Copyright (C) 2021 Julien JPK (jjpk.me)
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. */
+
+
+//! Selection state for interactive menus: fuzzy filtering, folder trees, cursor and scrolling
+
+
+use std::collections::BTreeMap;
+
+use fuzzy_matcher::FuzzyMatcher;
+use fuzzy_matcher::skim::SkimMatcherV2;
+
+
+/// Cursor movements shared by the flat menu and the tree
+pub trait Navigate {
+    /// Moves the cursor by the given amount, staying within the visible entries
+    fn move_by(&mut self, delta: isize);
+    fn home(&mut self);
+    fn end(&mut self);
+}
+
+/// Clamps a moved cursor within a list of the given length
+pub fn moved(cursor: usize, delta: isize, length: usize) -> usize {
+    match length {
+        0 => 0,
+        _ => (cursor as isize + delta).clamp(0, length as isize - 1) as usize
+    }
+}
+
+/// Adjusts a scrolling offset so that the cursor is visible in a window of the given height
+pub fn scrolled(cursor: usize, offset: usize, height: usize) -> usize {
+    let height = height.max(1);
+    if cursor < offset {
+        cursor
+    }
+    else if cursor >= offset + height {
+        cursor + 1 - height
+    }
+    else {
+        offset
+    }
+}
+
+/// Name of the file a relative path points to
+pub fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Groups relative paths by parent folder, sorted by folder, for two-level selection
+pub fn group_by_folder(choices: &[String]) -> Vec<(String, Vec<usize>)> {
+    let mut groups: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (i, c) in choices.iter().enumerate() {
+        let folder = match c.rfind('/') {
+            Some(p) => c[..p + 1].to_string(),
+            None => String::from("./")
+        };
+        groups.entry(folder).or_default().push(i);
+    }
+
+    for indices in groups.values_mut() {
+        indices.sort_by_key(|i| &choices[*i]);
+    }
+    groups.into_iter().collect()
+}
+
+
+/// An interactive menu over a list of choices
+pub struct Menu<'a> {
+    choices: &'a [String],
+    filter: String,
+    matches: Vec<usize>,
+    cursor: usize,
+    offset: usize
+}
+
+impl<'a> Menu<'a> {
+    pub fn new(choices: &'a [String]) -> Self {
+        Self {
+            choices,
+            filter: String::new(),
+            matches: (0..choices.len()).collect(),
+            cursor: 0,
+            offset: 0
+        }
+    }
+
+    /// Total number of choices
+    pub fn len(&self) -> usize {
+        self.choices.len()
+    }
+
+    /// Current filter string
+    pub fn filter(&self) -> &str {
+        &self.filter
+    }
+
+    /// Indices of the choices matching the filter, best matches first
+    pub fn matches(&self) -> &[usize] {
+        &self.matches
+    }
+
+    /// Recomputes matches after a filter change
+    fn refilter(&mut self) {
+        let matcher = SkimMatcherV2::default();
+        let mut scored: Vec<(i64, usize)> = self.choices.iter().enumerate()
+            .filter_map(|(i, c)| match self.filter.is_empty() {
+                true => Some((0, i)),
+                false => matcher.fuzzy_match(c, &self.filter).map(|s| (s, i))
+            })
+            .collect();
+
+        /* Stable: equally good matches keep their original order */
+        scored.sort_by_key(|s| std::cmp::Reverse(s.0));
+        self.matches = scored.into_iter().map(|(_, i)| i).collect();
+        self.cursor = 0;
+        self.offset = 0;
+    }
+
+    pub fn push(&mut self, c: char) {
+        self.filter.push(c);
+        self.refilter();
+    }
+
+    pub fn pop(&mut self) {
+        if self.filter.pop().is_some() {
+            self.refilter();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.filter.clear();
+        self.refilter();
+    }
+
+    /// Position of the cursor among the matches
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Index of the choice under the cursor, if anything matches
+    pub fn selection(&self) -> Option<usize> {
+        self.matches.get(self.cursor).copied()
+    }
+
+    /// Scrolls so that the cursor is visible in a window of the given height, returning the first visible match
+    pub fn scroll(&mut self, height: usize) -> usize {
+        self.offset = scrolled(self.cursor, self.offset, height);
+        self.offset
+    }
+}
+
+impl Navigate for Menu<'_> {
+    fn move_by(&mut self, delta: isize) {
+        self.cursor = moved(self.cursor, delta, self.matches.len());
+    }
+
+    fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    fn end(&mut self) {
+        self.cursor = self.matches.len().saturating_sub(1);
+    }
+}
+
+
+/// A folder of the tree, holding the indices of its playbooks
+struct Folder {
+    name: String,
+    folders: Vec<Folder>,
+    playbooks: Vec<usize>,
+    expanded: bool
+}
+
+impl Folder {
+    fn new(name: &str) -> Self {
+        Self { name: name.to_string(), folders: Vec::new(), playbooks: Vec::new(), expanded: false }
+    }
+
+    fn insert(&mut self, components: &[&str], index: usize) {
+        match components {
+            [] => (),
+            [_] => self.playbooks.push(index),
+            [name, rest @ ..] => {
+                let position = match self.folders.iter().position(|f| f.name == *name) {
+                    Some(p) => p,
+                    None => {
+                        self.folders.push(Folder::new(name));
+                        self.folders.len() - 1
+                    }
+                };
+                self.folders[position].insert(rest, index);
+            }
+        }
+    }
+
+    fn sort(&mut self, choices: &[String]) {
+        self.folders.sort_by(|a, b| a.name.cmp(&b.name));
+        self.playbooks.sort_by_key(|i| &choices[*i]);
+        self.folders.iter_mut().for_each(|f| f.sort(choices));
+    }
+
+    /// Number of playbooks in this folder and its subfolders
+    fn count(&self) -> usize {
+        self.playbooks.len() + self.folders.iter().map(|f| f.count()).sum::<usize>()
+    }
+}
+
+/// A visible line of the tree, folders being identified by their position from the root
+#[derive(Debug, PartialEq)]
+pub enum Row {
+    Folder { id: Vec<usize>, depth: usize, name: String, count: usize, expanded: bool },
+    Playbook { index: usize, parent: Vec<usize>, depth: usize }
+}
+
+/// A collapsible directory tree over relative playbook paths
+pub struct Tree {
+    root: Folder,
+    rows: Vec<Row>,
+    cursor: usize,
+    offset: usize
+}
+
+impl Tree {
+    pub fn new(choices: &[String]) -> Self {
+        let mut root = Folder::new("");
+        for (i, c) in choices.iter().enumerate() {
+            root.insert(&c.split('/').collect::<Vec<&str>>(), i);
+        }
+        root.sort(choices);
+
+        let mut tree = Self { root, rows: Vec::new(), cursor: 0, offset: 0 };
+        tree.refresh();
+        tree
+    }
+
+    /// Lines currently visible, collapsed folders hiding their content
+    pub fn rows(&self) -> &[Row] {
+        &self.rows
+    }
+
+    fn collect_rows(folder: &Folder, id: &mut Vec<usize>, rows: &mut Vec<Row>) {
+        for (i, f) in folder.folders.iter().enumerate() {
+            id.push(i);
+            rows.push(Row::Folder { id: id.clone(), depth: id.len() - 1, name: f.name.clone(), count: f.count(), expanded: f.expanded });
+            if f.expanded {
+                Self::collect_rows(f, id, rows);
+            }
+            id.pop();
+        }
+
+        for index in &folder.playbooks {
+            rows.push(Row::Playbook { index: *index, parent: id.clone(), depth: id.len() });
+        }
+    }
+
+    fn refresh(&mut self) {
+        let mut rows = Vec::new();
+        Self::collect_rows(&self.root, &mut Vec::new(), &mut rows);
+        self.rows = rows;
+        self.cursor = self.cursor.min(self.rows.len().saturating_sub(1));
+    }
+
+    fn set_expanded(&mut self, id: &[usize], expanded: bool) {
+        id.iter().fold(&mut self.root, |f, i| &mut f.folders[*i]).expanded = expanded;
+        self.refresh();
+    }
+
+    /// Position of the cursor among the visible rows
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Selects the playbook under the cursor, or toggles the folder under it
+    pub fn activate(&mut self) -> Option<usize> {
+        match self.rows.get(self.cursor) {
+            Some(Row::Playbook { index, .. }) => Some(*index),
+            Some(Row::Folder { id, expanded, .. }) => {
+                let (id, expanded) = (id.clone(), *expanded);
+                self.set_expanded(&id, !expanded);
+                None
+            },
+            None => None
+        }
+    }
+
+    /// Expands the folder under the cursor
+    pub fn expand(&mut self) {
+        if let Some(Row::Folder { id, expanded: false, .. }) = self.rows.get(self.cursor) {
+            let id = id.clone();
+            self.set_expanded(&id, true);
+        }
+    }
+
+    /// Collapses the folder under the cursor, or moves up to the parent folder
+    pub fn collapse(&mut self) {
+        let parent = match self.rows.get(self.cursor) {
+            Some(Row::Folder { id, expanded: true, .. }) => {
+                let id = id.clone();
+                return self.set_expanded(&id, false)
+            },
+            Some(Row::Folder { id, .. }) => id[..id.len() - 1].to_vec(),
+            Some(Row::Playbook { parent, .. }) => parent.clone(),
+            None => return
+        };
+
+        if let Some(p) = self.rows.iter().position(|r| matches!(r, Row::Folder { id, .. } if *id == parent)) {
+            self.cursor = p;
+        }
+    }
+
+    /// Scrolls so that the cursor is visible in a window of the given height, returning the first visible row
+    pub fn scroll(&mut self, height: usize) -> usize {
+        self.offset = scrolled(self.cursor, self.offset, height);
+        self.offset
+    }
+}
+
+impl Navigate for Tree {
+    fn move_by(&mut self, delta: isize) {
+        self.cursor = moved(self.cursor, delta, self.rows.len());
+    }
+
+    fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    fn end(&mut self) {
+        self.cursor = self.rows.len().saturating_sub(1);
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(tarpaulin_include))]
+mod tests {
+    use super::*;
+
+    fn choices() -> Vec<String> {
+        ["playbook1.yml", "depth1/playbook2.yml", "depth2/depth1/playbook3.yml", "other.yaml"]
+            .iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn no_filter_keeps_order() -> Result<(), String> {
+        let choices = choices();
+        let menu = Menu::new(&choices);
+        match menu.matches() == [0, 1, 2, 3] {
+            true => Ok(()),
+            false => Err(format!("unexpected matches: {:?}", menu.matches()))
+        }
+    }
+
+    #[test]
+    fn fuzzy_filter() -> Result<(), String> {
+        let choices = choices();
+        let mut menu = Menu::new(&choices);
+        "d2pb".chars().for_each(|c| menu.push(c));
+
+        if menu.selection() != Some(2) {
+            return Err(format!("unexpected matches: {:?}", menu.matches()))
+        }
+
+        menu.pop();
+        menu.pop();
+        match menu.matches().len() {
+            2 => Ok(()),
+            n => Err(format!("expected 2 matches for \"d2\", got {}", n))
+        }
+    }
+
+    #[test]
+    fn no_match_no_selection() -> Result<(), String> {
+        let choices = choices();
+        let mut menu = Menu::new(&choices);
+        "zzz".chars().for_each(|c| menu.push(c));
+        menu.move_by(1);
+        match menu.selection() {
+            None => Ok(()),
+            Some(i) => Err(format!("selected {} although nothing matches", i))
+        }
+    }
+
+    #[test]
+    fn cursor_and_scrolling() -> Result<(), String> {
+        let choices = choices();
+        let mut menu = Menu::new(&choices);
+
+        menu.move_by(10);
+        if menu.cursor() != 3 || menu.scroll(2) != 2 {
+            return Err("failed to scroll down to the last match".to_string())
+        }
+
+        menu.move_by(-2);
+        if menu.scroll(2) != 1 {
+            return Err("failed to scroll back up".to_string())
+        }
+
+        menu.home();
+        match (menu.selection(), menu.scroll(2)) {
+            (Some(0), 0) => Ok(()),
+            _ => Err("failed to go back to the first match".to_string())
+        }
+    }
+
+    fn tree_choices() -> Vec<String> {
+        ["b/two.yml", "top.yml", "a/one.yml", "b/c/three.yml", "b/c/four.yml"]
+            .iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn tree_collapsed() -> Result<(), String> {
+        let tree = Tree::new(&tree_choices());
+        let summary: Vec<(String, usize)> = tree.rows().iter().map(|r| match r {
+            Row::Folder { name, count, .. } => (name.clone(), *count),
+            Row::Playbook { index, .. } => (tree_choices()[*index].clone(), 0)
+        }).collect();
+
+        match summary == [("a".to_string(), 1), ("b".to_string(), 3), ("top.yml".to_string(), 0)] {
+            true => Ok(()),
+            false => Err(format!("unexpected rows: {:?}", summary))
+        }
+    }
+
+    #[test]
+    fn tree_expand_collapse() -> Result<(), String> {
+        let choices = tree_choices();
+        let mut tree = Tree::new(&choices);
+
+        /* b, then b/c */
+        tree.move_by(1);
+        tree.expand();
+        tree.move_by(1);
+        tree.activate();
+        tree.move_by(1);
+        if tree.rows().len() != 7 || tree.activate().map(|i| choices[i].as_str()) != Some("b/c/four.yml") {
+            return Err(format!("unexpected rows after expanding: {:?}", tree.rows()))
+        }
+
+        /* Up to b/c, collapse it, up to b */
+        tree.collapse();
+        tree.collapse();
+        tree.collapse();
+        match (tree.cursor(), tree.rows().len()) {
+            (1, 5) => Ok(()),
+            (c, n) => Err(format!("expected cursor on b with 5 rows, got {} and {}", c, n))
+        }
+    }
+
+    #[test]
+    fn grouped_by_folder() -> Result<(), String> {
+        let groups = group_by_folder(&tree_choices());
+        let expected = vec![
+            ("./".to_string(), vec![1]),
+            ("a/".to_string(), vec![2]),
+            ("b/".to_string(), vec![0]),
+            ("b/c/".to_string(), vec![4, 3])
+        ];
+
+        match groups == expected {
+            true => Ok(()),
+            false => Err(format!("unexpected groups: {:?}", groups))
+        }
+    }
+}
@@ -0,0 +1,141 @@
+/* Set Me Up, a minimalistic Ansible-based remote provisioning tool
+ * Copyright (C) 2021 Julien JPK (jjpk.me)
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. */
+
+
+//! Path patterns used to include or exclude playbooks: regexes and gitignore-style globs
+
+
+use std::path::Path;
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use regex::Regex;
+use yaml_rust::Yaml;
+
+
+/// Name of the ignore file read at the root of sources
+pub const IGNORE_FILE: &str = ".smuignore";
+
+/// A list of patterns, matched against paths relative to a source's root
+pub struct PathPatterns {
+    regexes: Vec<Regex>,
+    globs: Gitignore
+}
+
+impl Default for PathPatterns {
+    fn default() -> Self {
+        Self { regexes: Vec::new(), globs: Gitignore::empty() }
+    }
+}
+
+impl PathPatterns {
+    /// Parses a list of globs, or of {regex: ...} dictionaries, for the given source parameter
+    pub fn parse(yaml: &Yaml, parameter: &str) -> Result<Self, String> {
+        let items = match yaml {
+            Yaml::Array(a) => a,
+            Yaml::BadValue => return Ok(Self::default()),
+            _ => return Err(format!("expected list for the {} source parameter", parameter))
+        };
+
+        let mut regexes = Vec::new();
+        let mut builder = GitignoreBuilder::new("");
+        for item in items {
+            match (item, &item["regex"]) {
+                (Yaml::String(s), _) => {
+                    builder.add_line(None, s).map_err(|e| format!("invalid glob in the {} source parameter: {}", parameter, e))?;
+                },
+                (Yaml::Hash(_), Yaml::String(r)) => regexes.push(
+                    Regex::new(r).map_err(|e| format!("invalid regex in the {} source parameter: {}", parameter, e))?
+                ),
+                _ => return Err(format!("expected glob string or regex dictionary in the {} source parameter", parameter))
+            }
+        }
+
+        Ok(Self {
+            regexes,
+            globs: builder.build().map_err(|e| format!("invalid glob in the {} source parameter: {}", parameter, e))?
+        })
+    }
+
+    /// Reads the ignore file at the root of a source, if there is one
+    pub fn read_ignore_file(root: &Path) -> Result<Self, String> {
+        let path = root.join(IGNORE_FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let mut builder = GitignoreBuilder::new(root);
+        if let Some(e) = builder.add(&path) {
+            return Err(format!("failed to read {}: {}", path.display(), e));
+        }
+
+        Ok(Self {
+            regexes: Vec::new(),
+            globs: builder.build().map_err(|e| format!("failed to read {}: {}", path.display(), e))?
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regexes.is_empty() && self.globs.is_empty()
+    }
+
+    /// Checks a relative path itself against the patterns, negated globs taking precedence as in gitignore files
+    pub fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        self.regexes.iter().any(|r| r.is_match(&path.to_string_lossy())) || self.globs.matched(path, is_dir).is_ignore()
+    }
+
+    /// Checks a relative path and its parent directories against the patterns
+    pub fn matches_within(&self, path: &Path, is_dir: bool) -> bool {
+        self.regexes.iter().any(|r| r.is_match(&path.to_string_lossy()))
+            || self.globs.matched_path_or_any_parents(path, is_dir).is_ignore()
+    }
+}
+
+
+#[cfg(test)]
+#[cfg(not(tarpaulin_include))]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn patterns(yaml: &str) -> Result<PathPatterns, String> {
+        PathPatterns::parse(&YamlLoader::load_from_str(yaml).unwrap()[0], "include")
+    }
+
+    #[test]
+    fn globs_and_regexes() -> Result<(), String> {
+        let p = patterns("[\"public/\", \"*.test.yml\", \"!keep.test.yml\", {regex: \"^site\\\\.ya?ml$\"}]")?;
+        let checks = [
+            p.matches_within(Path::new("public/web.yml"), false),
+            p.matches(Path::new("roles/web.test.yml"), false),
+            p.matches(Path::new("keep.test.yml"), false),
+            p.matches(Path::new("site.yaml"), false),
+            p.matches(Path::new("other/site.yml"), false)
+        ];
+
+        match checks {
+            [true, true, false, true, false] => Ok(()),
+            c => Err(format!("unexpected matches: {:?}", c))
+        }
+    }
+
+    #[test]
+    fn invalid_patterns() -> Result<(), String> {
+        match (patterns("[{regex: \"(\"}]"), patterns("[42]"), patterns("\"*.yml\"")) {
+            (Err(_), Err(_), Err(_)) => Ok(()),
+            _ => Err("accepted invalid patterns".to_string())
+        }
+    }
+}
@@ -0,0 +1,266 @@
+/* Set Me Up, a minimalistic Ansible-based remote provisioning tool
+ * Copyright (C) 2021 Julien JPK (jjpk.me)
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. */
+
+
+//! What can be told about a playbook before running it: its name, description, hosts and tags
+
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value as Json};
+use yaml_rust::{Yaml, YamlLoader};
+
+
+/// Suffixes of the files holding playbook metadata next to them
+const SIDECAR_SUFFIXES: [&str; 2] = [".meta.yml", ".meta.yaml"];
+
+/// Extensions of the playbooks sidecar files may describe
+const PLAYBOOK_EXTENSIONS: [&str; 2] = [".yml", ".yaml"];
+
+/// Checks whether a file holds metadata for a playbook next to it, rather than being a playbook itself
+pub fn is_sidecar(path: &Path) -> bool {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    SIDECAR_SUFFIXES.iter().filter_map(|s| name.strip_suffix(s))
+        .any(|stem| PLAYBOOK_EXTENSIONS.iter().any(|e| path.with_file_name(format!("{}{}", stem, e)).is_file()))
+}
+
+/// Keys telling a play from any other mapping
+const PLAY_KEYS: [&str; 3] = ["hosts", "import_playbook", "ansible.builtin.import_playbook"];
+
+/// Checks whether a file holds a list of plays, failing if it cannot be parsed
+pub fn is_playbook(path: &Path) -> Result<bool, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let documents = YamlLoader::load_from_str(&content).map_err(|e| e.to_string())?;
+
+    Ok(match documents.first() {
+        Some(Yaml::Array(plays)) => !plays.is_empty() && plays.iter().all(|p| PLAY_KEYS.iter().any(|k| !p[*k].is_badvalue())),
+        _ => false
+    })
+}
+
+/// Possible metadata files for a playbook: site.yml is described by site.meta.yml
+pub fn sidecars(path: &Path) -> Vec<PathBuf> {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    SIDECAR_SUFFIXES.iter().map(|s| path.with_file_name(format!("{}{}", stem, s))).collect()
+}
+
+
+/// Playbook metadata shown when picking one
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PlaybookInfo {
+    /// Name of the first play
+    pub name: Option<String>,
+    /// From the sidecar file, or from the comment heading the playbook
+    pub description: Option<String>,
+    pub hosts: Vec<String>,
+    pub tags: Vec<String>
+}
+
+impl PlaybookInfo {
+    /// Reads what it can from a playbook, leaving out whatever is missing or invalid
+    pub fn read(path: &Path) -> Self {
+        let content = std::fs::read_to_string(path).unwrap_or_default();
+        let plays = YamlLoader::load_from_str(&content).ok()
+            .and_then(|d| d.into_iter().next())
+            .and_then(|d| d.into_vec())
+            .unwrap_or_default();
+
+        let mut hosts = BTreeSet::new();
+        let mut tags = BTreeSet::new();
+        for play in &plays {
+            match &play["hosts"] {
+                Yaml::String(h) => h.split(',').map(|h| h.trim().to_string()).filter(|h| !h.is_empty()).for_each(|h| { hosts.insert(h); }),
+                Yaml::Array(a) => a.iter().filter_map(|h| h.as_str()).for_each(|h| { hosts.insert(h.to_string()); }),
+                _ => ()
+            }
+            Self::collect_tags(play, &mut tags);
+        }
+
+        Self {
+            name: plays.first().and_then(|p| p["name"].as_str()).map(String::from),
+            description: Self::sidecar_description(path).or_else(|| Self::comment_description(&content)),
+            hosts: hosts.into_iter().collect(),
+            tags: tags.into_iter().collect()
+        }
+    }
+
+    /// Renders the information as JSON, as cached and sent to JSON clients
+    pub fn to_json(&self) -> Json {
+        json!({
+            "name": self.name,
+            "description": self.description,
+            "hosts": self.hosts,
+            "tags": self.tags
+        })
+    }
+
+    /// Reads back information rendered as JSON, None if it is invalid
+    pub fn from_json(json: &Json) -> Option<Self> {
+        let strings = |key: &str| json[key].as_array().map(|a| a.iter().filter_map(|s| s.as_str().map(String::from)).collect::<Vec<String>>());
+        Some(Self {
+            name: json["name"].as_str().map(String::from),
+            description: json["description"].as_str().map(String::from),
+            hosts: strings("hosts")?,
+            tags: strings("tags")?
+        })
+    }
+
+    /// Reads the description key from the first sidecar file found
+    fn sidecar_description(path: &Path) -> Option<String> {
+        sidecars(path).iter()
+            .find_map(|p| std::fs::read_to_string(p).ok())
+            .and_then(|c| YamlLoader::load_from_str(&c).ok())
+            .and_then(|d| d.into_iter().next())
+            .and_then(|d| d["description"].as_str().map(|s| s.trim().to_string()))
+    }
+
+    /// Reads the comment block at the top of a playbook, skipping any document start marker
+    fn comment_description(content: &str) -> Option<String> {
+        let lines: Vec<&str> = content.lines()
+            .map(|l| l.trim())
+            .skip_while(|l| l.is_empty() || *l == "---")
+            .take_while(|l| l.starts_with('#'))
+            .map(|l| l.trim_start_matches('#').trim())
+            .collect();
+
+        let description = lines.join("\n").trim().to_string();
+        match description.is_empty() {
+            true => None,
+            false => Some(description)
+        }
+    }
+
+    /// Gathers tags from plays, roles, tasks and blocks
+    fn collect_tags(yaml: &Yaml, tags: &mut BTreeSet<String>) {
+        match &yaml["tags"] {
+            Yaml::String(t) => t.split(',').map(|t| t.trim().to_string()).filter(|t| !t.is_empty()).for_each(|t| { tags.insert(t); }),
+            Yaml::Array(a) => a.iter().filter_map(|t| t.as_str()).for_each(|t| { tags.insert(t.to_string()); }),
+            _ => ()
+        }
+
+        for key in ["roles", "pre_tasks", "tasks", "post_tasks", "handlers", "block", "rescue", "always"].iter() {
+            if let Yaml::Array(items) = &yaml[*key] {
+                items.iter().for_each(|i| Self::collect_tags(i, tags));
+            }
+        }
+    }
+
+    /// A playbook's path, with its name when known
+    pub fn label(&self, path: &str) -> String {
+        match &self.name {
+            Some(n) => format!("{} - {}", path, n),
+            None => path.to_string()
+        }
+    }
+
+    /// Everything known about the playbook, line by line
+    pub fn details(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        if let Some(n) = &self.name {
+            lines.push(n.clone());
+        }
+        if let Some(d) = &self.description {
+            lines.extend(d.lines().map(String::from));
+        }
+        if !self.hosts.is_empty() {
+            lines.push(format!("Hosts: {}", self.hosts.join(", ")));
+        }
+        if !self.tags.is_empty() {
+            lines.push(format!("Tags: {}", self.tags.join(", ")));
+        }
+        lines
+    }
+}
+
+
+#[cfg(test)]
+#[cfg(not(tarpaulin_include))]
+mod tests {
+    use super::*;
+
+    fn get_playbook_path(name: &str) -> PathBuf {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR unset");
+        PathBuf::from(manifest_dir + "/tests/playbooks/" + name)
+    }
+
+    #[test]
+    fn commented_playbook() -> Result<(), String> {
+        let info = PlaybookInfo::read(&get_playbook_path("commented.yml"));
+        let expected = PlaybookInfo {
+            name: Some("Workstation".to_string()),
+            description: Some("Sets up a workstation.\nDotfiles included.".to_string()),
+            hosts: vec!["all".to_string(), "desktops".to_string()],
+            tags: vec!["dotfiles".to_string(), "packages".to_string(), "shell".to_string()]
+        };
+
+        match info == expected {
+            true => Ok(()),
+            false => Err(format!("unexpected playbook information: {:?}", info))
+        }
+    }
+
+    #[test]
+    fn sidecar_playbook() -> Result<(), String> {
+        let info = PlaybookInfo::read(&get_playbook_path("described.yml"));
+        match (info.name, info.description.as_deref()) {
+            (None, Some("Described in its sidecar file")) => Ok(()),
+            (n, d) => Err(format!("unexpected name {:?} and description {:?}", n, d))
+        }
+    }
+
+    #[test]
+    fn invalid_playbook() -> Result<(), String> {
+        match PlaybookInfo::read(&get_playbook_path("invalid.yml")) == PlaybookInfo::default() {
+            true => Ok(()),
+            false => Err("read information out of an invalid playbook".to_string())
+        }
+    }
+
+    #[test]
+    fn plays_detected() -> Result<(), String> {
+        let checks = ["commented.yml", "described.meta.yml", "invalid.yml"].iter()
+            .map(|p| is_playbook(&get_playbook_path(p)).ok())
+            .collect::<Vec<Option<bool>>>();
+
+        match checks.as_slice() {
+            [Some(true), Some(false), None] => Ok(()),
+            c => Err(format!("failed to tell playbooks from other files: {:?}", c))
+        }
+    }
+
+    #[test]
+    fn sidecar_detected() -> Result<(), String> {
+        let dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+        for name in ["site.yml", "site.meta.yml", "lone.meta.yml"].iter() {
+            std::fs::write(dir.path().join(name), "").map_err(|e| e.to_string())?;
+        }
+
+        match ["site.meta.yml", "site.yml", "lone.meta.yml"].iter().map(|n| is_sidecar(&dir.path().join(n))).collect::<Vec<bool>>().as_slice() {
+            [true, false, false] => Ok(()),
+            d => Err(format!("failed to tell sidecar files from playbooks: {:?}", d))
+        }
+    }
+
+    #[test]
+    fn info_round_trip() -> Result<(), String> {
+        let info = PlaybookInfo::read(&get_playbook_path("commented.yml"));
+        match PlaybookInfo::from_json(&info.to_json()) {
+            Some(i) if i == info => Ok(()),
+            i => Err(format!("failed to read back playbook information: {:?}", i))
+        }
+    }
+}
@@ -18,82 +18,292 @@
 //! Interacts with the client and actually provisions it
 
 
-use crate::ansible::AnsibleResult;
+use crate::ansible::{AnsibleContext, SessionMetadata};
+use crate::runs::{Run, RunSettings, KEY_FILE, CERTIFICATE_FILE, INVENTORY_FILE, SESSION_FILE, PLAY_FILE, SNAPSHOT_DIR};
 use crate::sources::Source;
+use crate::playbooks::PlaybookInfo;
 use crate::config::Config;
-use crate::setup::Setup;
+use crate::setup::{Setup, SSHAuthentication};
 use crate::ui::UI;
+use crate::shutdown::TrackedPath;
+use crate::snapshot;
+use crate::discovery;
+use crate::updates::{self, UpdatePolicy};
 
 use osshkeys::cipher::Cipher;
-use tempfile::NamedTempFile;
+use osshkeys::KeyPair;
 
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::fs::OpenOptions;
 use std::io::Write;
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::OpenOptionsExt;
 
 
+/// Picks the only choice without asking or lets the client pick one, telling whether they had a choice
+///
+/// `prompt` is only called with several choices, and returning None from it goes back. Having no choice is an error.
+fn pick<T>(mut choices: Vec<T>, what: &str, prompt: impl FnOnce(&[T]) -> Option<usize>) -> Result<Option<(T, bool)>, String> {
+    match choices.len() {
+        0 => Err(format!("no {} is available", what)),
+        1 => Ok(Some((choices.remove(0), false))),
+        _ => Ok(prompt(&choices).map(|i| (choices.swap_remove(i), true)))
+    }
+}
+
 /// Handles client interaction and triggers provisioning accordingly
 pub struct Provision<'a> {
-    setup: &'a Setup,
-    source: &'a Source,
-    playbook_path: PathBuf
+    pub source: &'a Source,
+    pub playbook_path: PathBuf,
+    /// What discovery read from the playbook
+    pub info: PlaybookInfo,
+    /// Whether the client picked the playbook, rather than it being the only one
+    pub picked: bool
 }
 
 #[cfg(not(tarpaulin_include))]
 impl<'a> Provision<'a> {
-    /// Prompts the client for a source and playbook
-    pub fn prompt(config: &'a Config, setup: &'a Setup) -> Result<Self, String> {
-        let source_index = UI.prompt_from_vec(
+    /// Updates a source as its failure policy says, returning whether it should be offered
+    ///
+    /// Sources are updated once per session, `updated` holding the outcome for those already updated.
+    fn update_source(source: &Source, updated: &mut HashMap<String, bool>) -> Result<bool, String> {
+        if let Some(offered) = updated.get(&source.name) {
+            return Ok(*offered);
+        }
+
+        let offered = match (source.update(), source.on_update_failure) {
+            (Ok(Some(age)), _) => {
+                UI.notice(&format!("Source {} updated {} ago", source.name, updates::format_age(age)));
+                true
+            },
+            (Ok(None), _) => true,
+            (Err(e), UpdatePolicy::Fail) => return Err(e),
+            (Err(e), UpdatePolicy::Warn) => {
+                UI.warning(&format!(
+                    "Failed to update source {}, carrying on with content {}:\n{}",
+                    source.name,
+                    match source.last_update() {
+                        Some(age) => format!("updated {} ago", updates::format_age(age)),
+                        None => "of unknown age".to_string()
+                    },
+                    e.trim_end()
+                ));
+                true
+            },
+            (Err(_), UpdatePolicy::Hide) => false
+        };
+        updated.insert(source.name.clone(), offered);
+        Ok(offered)
+    }
+
+    /// Prompts the client for a source, unless there is only one, and updates it, telling whether the client had a choice
+    ///
+    /// Sources hidden when they fail to update are updated first, to be left out of the menu.
+    pub fn prompt_source(config: &'a Config, updated: &mut HashMap<String, bool>) -> Result<(&'a Source, bool), String> {
+        let mut sources = Vec::new();
+        for source in &config.sources {
+            if source.on_update_failure != UpdatePolicy::Hide || Self::update_source(source, updated)? {
+                sources.push(source);
+            }
+        }
+
+        let (source, picked) = pick(sources, "source", |sources| UI.prompt_from_vec(
             "Here are the available provisioning sources:",
-            &config.sources.iter().map(|s| s.name.clone()).collect()
-        );
+            &sources.iter().map(|s| s.name.clone()).collect::<Vec<String>>(),
+            false
+        ))?.unwrap();
+
+        Self::update_source(source, updated)?;
+        Ok((source, picked))
+    }
 
-        let source = config.sources.get(source_index).unwrap();
-        source.update()?;
+    /// Prompts the client for a playbook from the source, unless there is only one, or None to go back if allowed
+    ///
+    /// A source without playbooks sends the client back when possible, and is an error otherwise.
+    pub fn prompt_playbook(source: &'a Source, back: bool) -> Result<Option<Self>, String> {
+        let discovered = source.explore();
+        for warning in &discovered.warnings {
+            UI.warning(warning);
+        }
 
-        let playbooks = source.explore();
-        let playbook_index = UI.prompt_from_vec(
+        if discovered.playbooks.is_empty() && back {
+            UI.error(&format!("No playbook is available in source {}", source.name));
+            return Ok(None);
+        }
+
+        let infos = discovered.infos;
+        let picked = pick(discovered.playbooks.into_iter().zip(&infos).collect(), "playbook", |playbooks| UI.prompt_playbook(
             "Here are the available playbooks:",
-            &playbooks.iter().map(|p| String::from(p.as_path().to_str().unwrap())).collect()
-        );
-        let playbook_path = playbooks[playbook_index].clone();
+            &playbooks.iter().map(|(p, _)| p.to_str().unwrap().to_string()).collect::<Vec<String>>(),
+            &infos,
+            source.recurse,
+            back
+        ))?;
+        Ok(picked.map(|((playbook_path, info), picked)| Self { source, playbook_path, info: info.clone(), picked }))
+    }
 
-        Ok(Self {
-            setup,
-            source,
-            playbook_path
-        })
+    /// Prompts the client for a playbook among those of all sources, which are all updated first
+    pub fn prompt_any_playbook(config: &'a Config, updated: &mut HashMap<String, bool>) -> Result<Self, String> {
+        let mut playbooks = Vec::new();
+        let mut playbook_infos = Vec::new();
+        for source in &config.sources {
+            if !Self::update_source(source, updated)? {
+                continue;
+            }
+            let discovered = source.explore();
+            for warning in discovered.warnings {
+                UI.warning(&format!("{}: {}", source.name, warning));
+            }
+            playbooks.extend(discovered.playbooks.into_iter().map(|p| (source, p)));
+            playbook_infos.extend(discovered.infos);
+        }
+
+        let choices = playbooks.into_iter().zip(&playbook_infos).collect();
+        let (((source, playbook_path), info), picked) = pick(choices, "playbook", |playbooks| UI.prompt_playbook(
+            "Here are the available playbooks:",
+            &playbooks.iter().map(|((s, p), _)| format!("{} / {}", s.name, p.to_str().unwrap())).collect::<Vec<String>>(),
+            &playbook_infos,
+            false,
+            false
+        ))?.unwrap();
+        Ok(Self { source, playbook_path, info: info.clone(), picked })
     }
 
-    /// Runs ansible-playbook and provisions the client
-    pub fn execute(&self) -> Result<AnsibleResult, String> {
-        /* Put the key on disk */
-        let mut keyfile = NamedTempFile::new().map_err(|e| format!("failed to ready the private key: {}", e))?;
+    /// Writes the private key and its certificate to the run directory
+    fn write_key(run: &Run, keypair: &KeyPair, certificate: Option<&str>) -> Result<PathBuf, String> {
+        let key_path = run.path(KEY_FILE);
 
-        keyfile.path().metadata()
-            .map_err(|e| format!("failed to secure the private key file: {}", e))?
-            .permissions().set_mode(0o600);
+        let mut keyfile = OpenOptions::new().write(true).create_new(true).mode(0o600).open(&key_path)
+            .map_err(|e| format!("failed to secure the private key file: {}", e))?;
 
-        keyfile.write(
-            self.setup.credentials.keypair
-                .serialize_openssh(None, Cipher::Null)
+        keyfile.write_all(
+            keypair.serialize_openssh(None, Cipher::Null)
                 .map_err(|e| format!("failed to serialise the private key: {}", e))?.as_bytes())
             .map_err(|e| format!("failed to write the private key to disk: {}", e))?;
 
-        /* Create the inventory file */
-        let mut inventory = NamedTempFile::new().map_err(|e| format!("failed to ready the inventory file: {}", e))?;
-        inventory.write(
-            format!("provisionee ansible_host=127.0.0.1 ansible_port={} ansible_user={}",
-                    self.setup.reverse_port,
-                    self.setup.credentials.username).as_bytes()
+        /* ssh picks up <key>-cert.pub on its own */
+        if let Some(c) = certificate {
+            std::fs::write(run.path(CERTIFICATE_FILE), c)
+                .map_err(|e| format!("failed to write the certificate to disk: {}", e))?;
+        }
+
+        Ok(key_path)
+    }
+
+    /// Writes what ansible-playbook needs to the run directory and starts it
+    fn start(&self, run: &Run, setup: &Setup, detach: bool) -> Result<(), String> {
+        /* Hooks run from the source itself, whether or not the playbook runs from a snapshot */
+        let variables: HashMap<String, String> = vec![
+            ("SMU_SOURCE", self.source.name.clone()),
+            ("SMU_PLAYBOOK", self.playbook_path.to_str().unwrap().to_string()),
+            ("SMU_CLIENT_USER", setup.credentials.username.clone()),
+            ("SMU_PORT", setup.reverse_port.to_string())
+        ].into_iter().map(|(k, v)| (k.to_string(), v)).collect();
+        run.write_hooks(&self.source.hooks, &self.source.path, &variables)?;
+
+        /* Put the key on disk, unless using the agent */
+        let key_path = match &setup.credentials.authentication {
+            SSHAuthentication::Agent => None,
+            SSHAuthentication::Key(keypair, certificate) => Some(Self::write_key(run, keypair, certificate.as_deref())?)
+        };
+
+        /* Create the inventory file and the play */
+        let inventory_path = run.path(INVENTORY_FILE);
+        std::fs::write(
+            &inventory_path,
+            self.source.ansible.inventory(setup.reverse_port, &setup.credentials.username)
         ).map_err(|e| format!("failed to write the inventory: {}", e))?;
 
+        /* Run from a snapshot of the source, which other sessions' updates cannot touch */
+        let (source_path, revision) = match self.source.snapshot {
+            Some(mode) => {
+                let _lock = self.source.lock()?;
+                snapshot::take(mode, &self.source.path, &run.path(SNAPSHOT_DIR))?;
+                (run.path(SNAPSHOT_DIR), discovery::git_head(&self.source.path))
+            },
+            None => (self.source.path.clone(), discovery::git_head(&self.source.path))
+        };
+
+        /* Session variables go through a file, keeping them out of the command line and away from templating */
+        let session_path = run.path(SESSION_FILE);
+        std::fs::write(
+            &session_path,
+            SessionMetadata::new(setup.credentials.username.clone(), setup.reverse_port, self.source.name.clone(),
+                                 revision, self.playbook_path.to_str().unwrap().to_string()).to_yaml()
+        ).map_err(|e| format!("failed to write the session variables: {}", e))?;
+
+        let play_path = run.path(PLAY_FILE);
+        AnsibleContext::write_play(&play_path, &source_path.join(&self.playbook_path),
+                                   setup.credentials.cleanup_key.as_deref())?;
+
         /* Call ansible-playbook */
-        self.source.ansible.execute(
-            keyfile.path(),
-            inventory.path(),
-            self.playbook_path.as_path(),
-            self.source.path.as_path()
-        )
+        let command = self.source.ansible.command(
+            key_path.as_deref(),
+            &inventory_path,
+            &session_path,
+            &play_path,
+            &source_path
+        );
+
+        run.start(&command, detach)
+    }
+
+    /// Starts ansible-playbook to provision the client, the after-run hooks being run at once if it cannot be started
+    pub fn execute(&self, setup: &Setup, settings: &RunSettings) -> Result<Run, String> {
+        let run = Run::create(settings)?;
+        UI.run_started(&run.id, settings.detach);
+        UI.running();
+
+        /* Until ansible-playbook is started, cleaning up after the run is up to this session */
+        let mut tracked: Vec<TrackedPath> = [run.path(KEY_FILE), run.path(CERTIFICATE_FILE),
+                                             run.path(SNAPSHOT_DIR), run.path(INVENTORY_FILE), run.path(SESSION_FILE),
+                                             run.path(PLAY_FILE)]
+            .iter().map(|p| TrackedPath::new(p)).collect();
+
+        if let Err(e) = self.start(&run, setup, settings.detach) {
+            Run::clean_up(&run.dir);
+            for (hook, error) in run.run_hooks(false, |hook, line| UI.hook_output(hook, line)) {
+                UI.warning(&format!("The {} hook failed:\n{}", hook, error));
+            }
+            return Err(e);
+        }
+
+        /* A detached supervisor cleans up after itself, this session must not get in its way */
+        if settings.detach {
+            tracked.clear();
+        }
+        Ok(run)
+    }
+}
+
+
+#[cfg(test)]
+#[cfg(not(tarpaulin_include))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pick_without_choice() -> Result<(), String> {
+        match pick(Vec::<&str>::new(), "playbook", |_| panic!("prompted without a choice")) {
+            Err(e) if e == "no playbook is available" => Ok(()),
+            r => Err(format!("unexpected pick: {:?}", r))
+        }
+    }
+
+    #[test]
+    fn pick_single_choice() -> Result<(), String> {
+        match pick(vec!["site.yml"], "playbook", |_| panic!("prompted with a single choice"))? {
+            Some(("site.yml", false)) => Ok(()),
+            r => Err(format!("unexpected pick: {:?}", r))
+        }
+    }
+
+    #[test]
+    fn pick_among_choices() -> Result<(), String> {
+        match (pick(vec!["a.yml", "b.yml", "c.yml"], "playbook", |c| c.iter().position(|p| *p == "b.yml"))?,
+               pick(vec!["a.yml", "b.yml"], "playbook", |_| None)?) {
+            (Some(("b.yml", true)), None) => Ok(()),
+            r => Err(format!("unexpected picks: {:?}", r))
+        }
     }
 }
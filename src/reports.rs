@@ -0,0 +1,203 @@
+/* Set Me Up, a minimalistic Ansible-based remote provisioning tool
+ * Copyright (C) 2021 Julien JPK (jjpk.me)
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. */
+
+
+//! JUnit XML and Markdown reports of provisioning results
+
+
+use crate::ansible::AnsibleResult;
+
+use yaml_rust::Yaml;
+
+
+/// Report formats, all of which are written to the run directory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReportFormat {
+    JUnit,
+    Markdown
+}
+
+impl ReportFormat {
+    pub const ALL: [ReportFormat; 2] = [ReportFormat::JUnit, ReportFormat::Markdown];
+
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "junit" => Ok(Self::JUnit),
+            "markdown" => Ok(Self::Markdown),
+            _ => Err(format!("unsupported report format {} (expected junit or markdown)", name))
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::JUnit => "junit",
+            Self::Markdown => "markdown"
+        }
+    }
+
+    /// Name of the report in the run directory
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            Self::JUnit => "report.xml",
+            Self::Markdown => "report.md"
+        }
+    }
+
+    pub fn render(&self, result: &AnsibleResult, run_id: &str) -> String {
+        match self {
+            Self::JUnit => junit(result, run_id),
+            Self::Markdown => markdown(result, run_id)
+        }
+    }
+}
+
+
+/// Which report, if any, should also be printed once the run is over
+#[derive(Default)]
+pub struct ReportSettings {
+    pub stdout: Option<ReportFormat>
+}
+
+impl ReportSettings {
+    /// Parses the reports configuration block
+    pub fn parse(yaml: &Yaml) -> Result<Self, String> {
+        Ok(Self {
+            stdout: match &yaml["stdout"] {
+                Yaml::String(s) => Some(ReportFormat::from_name(s)?),
+                Yaml::BadValue => None,
+                _ => return Err("expected string for the reports stdout parameter".to_string())
+            }
+        })
+    }
+}
+
+
+/// Escapes text for XML, dropping the control characters it cannot hold, such as ANSI escapes
+fn escape_xml(s: &str) -> String {
+    s.chars().filter(|c| *c >= ' ' || matches!(c, '\t' | '\n' | '\r')).collect::<String>()
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// One testcase per task, failed tasks carrying their error message
+fn junit(result: &AnsibleResult, run_id: &str) -> String {
+    let failures = result.iter().filter(|t| !t.success).count();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!("<testsuites name=\"setmeup\" tests=\"{}\" failures=\"{}\">\n", result.len(), failures));
+    xml.push_str(&format!("  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\">\n", escape_xml(run_id), result.len(), failures));
+
+    for task in result {
+        let mut testcase = format!("    <testcase classname=\"{}\" name=\"{}\"", escape_xml(run_id), escape_xml(&task.name));
+        if let Some(d) = task.duration {
+            testcase.push_str(&format!(" time=\"{:.3}\"", d));
+        }
+
+        match (task.success, task.changed) {
+            (true, false) => xml.push_str(&format!("{}/>\n", testcase)),
+            (true, true) => xml.push_str(&format!("{}>\n      <system-out>changed</system-out>\n    </testcase>\n", testcase)),
+            (false, _) => xml.push_str(&format!("{}>\n      <failure message=\"{}\">{}</failure>\n    </testcase>\n",
+                                                testcase, escape_xml(&task.message), escape_xml(&task.message)))
+        }
+    }
+
+    xml.push_str("  </testsuite>\n</testsuites>\n");
+    xml
+}
+
+/// A summary line, a table of tasks and the failure messages
+fn markdown(result: &AnsibleResult, run_id: &str) -> String {
+    let failed: Vec<_> = result.iter().filter(|t| !t.success).collect();
+    let cell = |s: &str| s.replace('|', "\\|").replace('\n', " ");
+
+    let mut md = format!("# Provisioning run {}\n\n**{} tasks**: {} ok, {} changed, {} failed\n\n\
+                          | Task | Status | Changed |\n|------|--------|---------|\n",
+                         run_id, result.len(), result.len() - failed.len(),
+                         result.iter().filter(|t| t.changed).count(), failed.len());
+
+    for task in result {
+        md.push_str(&format!("| {} | {} | {} |\n", cell(&task.name),
+                             if task.success { "ok" } else { "**failed**" },
+                             if task.changed { "yes" } else { "no" }));
+    }
+
+    if !failed.is_empty() {
+        md.push_str("\n## Failures\n");
+        for task in failed {
+            md.push_str(&format!("\n### {}\n\n```\n{}\n```\n", task.name, task.message));
+        }
+    }
+
+    md
+}
+
+
+#[cfg(test)]
+#[cfg(not(tarpaulin_include))]
+mod tests {
+    use super::*;
+    use crate::ansible::AnsibleTaskResult;
+
+    fn result() -> AnsibleResult {
+        vec![
+            AnsibleTaskResult { name: "Install <packages>".to_string(), success: true, changed: true, duration: Some(2.5), ..Default::default() },
+            AnsibleTaskResult { name: "Gather facts".to_string(), success: true, changed: false, ..Default::default() },
+            AnsibleTaskResult { name: "Copy | dotfiles".to_string(), success: false, changed: false, message: "\"src\" not found".to_string(), ..Default::default() }
+        ]
+    }
+
+    #[test]
+    fn junit_report() -> Result<(), String> {
+        let xml = junit(&result(), "run");
+        let expected = [
+            "<testsuite name=\"run\" tests=\"3\" failures=\"1\">",
+            "<testcase classname=\"run\" name=\"Install &lt;packages&gt;\" time=\"2.500\">",
+            "<testcase classname=\"run\" name=\"Gather facts\"/>",
+            "<failure message=\"&quot;src&quot; not found\">"
+        ];
+
+        match expected.iter().find(|e| !xml.contains(*e)) {
+            Some(e) => Err(format!("missing {} from report:\n{}", e, xml)),
+            None => Ok(())
+        }
+    }
+
+    #[test]
+    fn junit_control_characters() -> Result<(), String> {
+        let result = vec![AnsibleTaskResult { name: "\x1b[31mred\x1b[0m\x07".to_string(), success: true, ..Default::default() }];
+        match junit(&result, "run").contains("name=\"[31mred[0m\"") {
+            true => Ok(()),
+            false => Err("failed to drop control characters".to_string())
+        }
+    }
+
+    #[test]
+    fn markdown_report() -> Result<(), String> {
+        let md = markdown(&result(), "run");
+        let expected = [
+            "**3 tasks**: 2 ok, 1 changed, 1 failed",
+            "| Copy \\| dotfiles | **failed** | no |",
+            "### Copy | dotfiles\n\n```\n\"src\" not found\n```"
+        ];
+
+        match expected.iter().find(|e| !md.contains(*e)) {
+            Some(e) => Err(format!("missing {} from report:\n{}", e, md)),
+            None => Ok(())
+        }
+    }
+}
@@ -0,0 +1,557 @@
+/* Set Me Up, a minimalistic Ansible-based remote provisioning tool
+ * Copyright (C) 2021 Julien JPK (jjpk.me)
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. */
+
+
+//! Run directories: journaled ansible-playbook runs, optionally detached from the SSH session
+
+
+use crate::ansible::{AnsibleCommand, AnsibleEvents, AnsibleResult, AnsibleTaskResult};
+use crate::audit;
+use crate::exec;
+use crate::hooks::Hooks;
+use crate::reports::ReportFormat;
+use crate::shutdown::TrackedChild;
+use crate::snapshot;
+use crate::ui::UI;
+
+use std::collections::HashMap;
+use std::fs::{DirBuilder, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use chrono::Local;
+use directories::ProjectDirs;
+use serde_json::{json, Value as Json};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use yaml_rust::Yaml;
+
+
+/// Where runs are journaled and whether they should outlive the SSH session
+pub struct RunSettings {
+    pub directory: PathBuf,
+    pub detach: bool
+}
+
+impl RunSettings {
+    /// Parses the runs configuration block
+    pub fn parse(yaml: &Yaml) -> Result<Self, String> {
+        let defaults = Self::default();
+        Ok(Self {
+            directory: match &yaml["directory"] {
+                Yaml::String(s) => PathBuf::from(s),
+                Yaml::BadValue => defaults.directory,
+                _ => return Err("expected string for the runs directory".to_string())
+            },
+
+            detach: match &yaml["detach"] {
+                Yaml::Boolean(b) => *b,
+                Yaml::BadValue => defaults.detach,
+                _ => return Err("expected boolean for the runs detach parameter".to_string())
+            }
+        })
+    }
+}
+
+impl Default for RunSettings {
+    /// Attached runs, journaled in the user's data directory
+    fn default() -> Self {
+        Self {
+            directory: match ProjectDirs::from("me", "jjpk", "setmeup") {
+                Some(d) => d.data_dir().join("runs"),
+                None => std::env::temp_dir().join("setmeup-runs")
+            },
+            detach: false
+        }
+    }
+}
+
+
+/// Files making up a run directory
+pub const KEY_FILE: &str = "id";
+pub const CERTIFICATE_FILE: &str = "id-cert.pub";
+pub const INVENTORY_FILE: &str = "inventory";
+pub const SESSION_FILE: &str = "session.yml";
+pub const PLAY_FILE: &str = "play.yml";
+pub const SNAPSHOT_DIR: &str = "source";
+const COMMAND_FILE: &str = "command.json";
+const JOURNAL_FILE: &str = "journal.jsonl";
+const STDERR_FILE: &str = "stderr.log";
+const PID_FILE: &str = "pid";
+const SUPERVISOR_PID_FILE: &str = "supervisor.pid";
+const STATUS_FILE: &str = "exit_status";
+const HOOKS_FILE: &str = "hooks.json";
+const HOOKS_LOG_FILE: &str = "hooks.jsonl";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How long a supervisor gets to start ansible-playbook before its run is considered dead
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A single provisioning run and its directory
+pub struct Run {
+    pub id: String,
+    pub dir: PathBuf
+}
+
+impl Run {
+    /// Creates a new run directory, its ID made unguessable as it is all it takes to attach to the run
+    pub fn create(settings: &RunSettings) -> Result<Self, String> {
+        let mut random = [0u8; 8];
+        std::fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut random))
+            .map_err(|e| format!("failed to generate a run ID: {}", e))?;
+        let id = format!("{}-{}", Local::now().format("%Y%m%d-%H%M%S"),
+                         random.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+        let dir = settings.directory.join(&id);
+
+        DirBuilder::new().recursive(true).mode(0o700).create(&dir)
+            .map_err(|e| format!("failed to create run directory {}: {}", dir.to_str().unwrap(), e))?;
+
+        Ok(Self { id, dir })
+    }
+
+    /// Opens an existing run directory
+    pub fn open(settings: &RunSettings, id: &str) -> Result<Self, String> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(format!("invalid run ID: {}", id))
+        }
+
+        let dir = settings.directory.join(id);
+        match dir.is_dir() {
+            true => Ok(Self { id: id.to_string(), dir }),
+            false => Err(format!("no such run: {}", id))
+        }
+    }
+
+    /// Returns the path to a file in the run directory
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// Starts ansible-playbook under a supervisor, detached from the SSH session if required
+    ///
+    /// An attached supervisor is stopped along with this session, running the after-run hooks as it goes.
+    #[cfg(not(tarpaulin_include))]
+    pub fn start(&self, command: &AnsibleCommand, detach: bool) -> Result<(), String> {
+        std::fs::write(self.path(COMMAND_FILE), Self::serialise_command(command).to_string())
+            .map_err(|e| format!("failed to write the run command: {}", e))?;
+
+        let executable = std::env::current_exe().map_err(|e| format!("failed to locate setmeup: {}", e))?;
+        let mut supervisor = Command::new(executable);
+        supervisor.arg("--supervise").arg(&self.dir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+
+        match detach {
+            true => {
+                /* A new session keeps the supervisor out of reach of the SSH session's hangup */
+                unsafe {
+                    supervisor.pre_exec(|| {
+                        libc::setsid();
+                        Ok(())
+                    });
+                }
+
+                /* Reaped, so that it does not outlive the run as a zombie */
+                let mut child = supervisor.spawn().map_err(|e| format!("failed to start the supervisor: {}", e))?;
+                std::thread::spawn(move || child.wait().ok());
+                Ok(())
+            },
+
+            false => {
+                let mut child = supervisor.process_group(0).spawn().map_err(|e| format!("failed to start the supervisor: {}", e))?;
+                let tracked = TrackedChild::new(child.id());
+                std::thread::spawn(move || {
+                    child.wait().ok();
+                    drop(tracked);
+                });
+                Ok(())
+            }
+        }
+    }
+
+    /// Runs ansible-playbook from a run directory until it completes, then the after-run hooks, as a supervisor
+    #[cfg(not(tarpaulin_include))]
+    pub fn supervise(dir: &Path) -> i32 {
+        let run = Self { id: String::new(), dir: dir.to_path_buf() };
+        std::fs::write(run.path(SUPERVISOR_PID_FILE), std::process::id().to_string()).ok();
+
+        /* Hook failures go to the audit log of the session which started the run */
+        audit::init(std::fs::read_to_string(run.path(HOOKS_FILE)).ok()
+            .and_then(|c| serde_json::from_str::<Json>(&c).ok())
+            .and_then(|h| h["audit_log"].as_str().map(PathBuf::from)));
+
+        /* Stopping the supervisor stops ansible-playbook, the run going on to its hooks as a failure */
+        if let Ok(mut signals) = Signals::new([SIGHUP, SIGINT, SIGTERM]) {
+            let pid_file = run.path(PID_FILE);
+            std::thread::spawn(move || {
+                for _ in signals.forever() {
+                    if let Some(pid) = std::fs::read_to_string(&pid_file).ok().and_then(|p| p.trim().parse::<i32>().ok()) {
+                        unsafe { libc::killpg(pid, libc::SIGTERM); }
+                    }
+                }
+            });
+        }
+
+        let command = std::fs::read_to_string(run.path(COMMAND_FILE)).map_err(|e| e.to_string())
+            .and_then(|c| serde_json::from_str::<Json>(&c).map_err(|e| e.to_string()))
+            .and_then(|c| Self::deserialise_command(&c));
+
+        let status = match command.and_then(|c| run.spawn(&c)) {
+            Ok(mut child) => {
+                let status = child.wait().ok().and_then(|s| s.code()).unwrap_or(-1);
+                Self::clean_up(dir);
+                std::fs::write(run.path(STATUS_FILE), status.to_string()).ok();
+                status
+            },
+            Err(e) => {
+                Self::clean_up(dir);
+                std::fs::write(run.path(STDERR_FILE), e).ok();
+                std::fs::write(run.path(STATUS_FILE), "-1").ok();
+                -1
+            }
+        };
+
+        run.log_hooks(status == 0);
+        status
+    }
+
+    /// Starts ansible-playbook with its output journaled to the run directory
+    fn spawn(&self, command: &AnsibleCommand) -> Result<Child, String> {
+        let child = exec::spawn(&command.program, &command.args, &command.working_dir, Some(&command.env),
+                                &self.path(JOURNAL_FILE), &self.path(STDERR_FILE))?;
+        std::fs::write(self.path(PID_FILE), child.id().to_string())
+            .map_err(|e| format!("failed to write the run PID: {}", e))?;
+        Ok(child)
+    }
+
+    /// Removes the credentials and source snapshot once ansible-playbook is gone, or was never started
+    pub fn clean_up(dir: &Path) {
+        std::fs::remove_file(dir.join(KEY_FILE)).ok();
+        std::fs::remove_file(dir.join(CERTIFICATE_FILE)).ok();
+        if dir.join(SNAPSHOT_DIR).exists() {
+            snapshot::remove(&dir.join(SNAPSHOT_DIR));
+        }
+    }
+
+    /// Records the hooks to run once the run is over, from the given source directory and with the given variables
+    pub fn write_hooks(&self, hooks: &Hooks, source_path: &Path, variables: &HashMap<String, String>) -> Result<(), String> {
+        if !hooks.any_after_run() {
+            return Ok(());
+        }
+
+        std::fs::write(self.path(HOOKS_FILE), json!({
+            "hooks": hooks.to_json(),
+            "source": source_path.to_str().unwrap(),
+            "variables": variables,
+            "audit_log": audit::path().map(|p| p.to_string_lossy().into_owned())
+        }).to_string()).map_err(|e| format!("failed to write the run hooks: {}", e))
+    }
+
+    /// Runs the recorded after-run hooks, handing their streamed output over as it comes, and returns their failures
+    pub fn run_hooks<F: FnMut(&str, &str)>(&self, success: bool, mut on_line: F) -> Vec<(String, String)> {
+        let stored = match std::fs::read_to_string(self.path(HOOKS_FILE)).ok().and_then(|c| serde_json::from_str::<Json>(&c).ok()) {
+            Some(s) => s,
+            None => return Vec::new()
+        };
+
+        let hooks = match Hooks::from_json(&stored["hooks"]) {
+            Ok(h) => h,
+            Err(e) => return vec![("post_provision".to_string(), e)]
+        };
+        let mut variables: HashMap<String, String> = stored["variables"].as_object().map(|v| v.iter()
+            .filter_map(|(k, v)| v.as_str().map(|v| (k.clone(), v.to_string()))).collect()).unwrap_or_default();
+        variables.insert("SMU_RESULT".to_string(), String::from(if success { "success" } else { "failure" }));
+
+        let source_path = PathBuf::from(stored["source"].as_str().unwrap_or("."));
+        let mut failures = Vec::new();
+        for (name, hook) in hooks.after_run(success) {
+            if let Err(e) = hook.run(&source_path, &variables, |line| on_line(name, line)) {
+                audit::log(&format!("{} hook failed: {}", name, e.trim_end()));
+                failures.push((name.to_string(), e.trim_end().to_string()));
+            }
+        }
+        failures
+    }
+
+    /// Runs the after-run hooks, logging their output and failures for the sessions following the run
+    fn log_hooks(&self, success: bool) {
+        if !self.path(HOOKS_FILE).exists() {
+            return;
+        }
+
+        let mut log = match OpenOptions::new().create(true).append(true).open(self.path(HOOKS_LOG_FILE)) {
+            Ok(l) => l,
+            Err(_) => return
+        };
+
+        let failures = self.run_hooks(success, |hook, line| {
+            writeln!(log, "{}", json!({ "hook": hook, "line": line })).ok();
+        });
+        for (hook, error) in failures {
+            writeln!(log, "{}", json!({ "hook": hook, "error": error })).ok();
+        }
+        writeln!(log, "{}", json!({ "done": true })).ok();
+    }
+
+    /// Checks whether the process whose PID is in the given file is still there
+    fn pid_is_alive(pid_file: &Path) -> bool {
+        std::fs::read_to_string(pid_file).ok().and_then(|p| p.trim().parse::<i32>().ok())
+            .is_some_and(|pid| unsafe { libc::kill(pid, 0) == 0 })
+    }
+
+    /// Checks whether the process running the playbook is still there, or about to be started by a supervisor
+    fn is_alive(&self) -> bool {
+        match self.path(PID_FILE).exists() {
+            true => Self::pid_is_alive(&self.path(PID_FILE)),
+            false => std::fs::metadata(self.path(COMMAND_FILE)).and_then(|m| m.modified()).ok()
+                .and_then(|m| m.elapsed().ok())
+                .is_some_and(|age| age < START_TIMEOUT)
+        }
+    }
+
+    /// Replays the journal from the start and streams it until ansible-playbook exits
+    pub fn follow<F: FnMut(usize, &AnsibleTaskResult)>(&self, mut on_task: F) -> Result<AnsibleResult, String> {
+        let mut events = AnsibleEvents::new();
+        let mut journal = None;
+        let mut buffer = Vec::new();
+
+        loop {
+            /* Checked before reading so that nothing written before exiting is missed */
+            let status = std::fs::read_to_string(self.path(STATUS_FILE)).ok();
+            let alive = status.is_some() || self.is_alive();
+
+            if journal.is_none() {
+                journal = std::fs::File::open(self.path(JOURNAL_FILE)).ok();
+            }
+
+            if let Some(j) = journal.as_mut() {
+                j.read_to_end(&mut buffer).map_err(|e| format!("failed to read the run journal: {}", e))?;
+
+                /* Only hand over complete UTF-8 sequences */
+                let valid = match std::str::from_utf8(&buffer) {
+                    Ok(s) => s.len(),
+                    Err(e) => e.valid_up_to()
+                };
+                let before = events.results.len();
+                let new = events.feed(std::str::from_utf8(&buffer[..valid]).unwrap());
+                buffer.drain(..valid);
+                for (i, task) in events.results[before..before + new].iter().enumerate() {
+                    on_task(before + i + 1, task);
+                }
+            }
+
+            match (status, alive) {
+                (Some(s), _) => return match events.complete {
+                    true => {
+                        /* The run itself went through, a missing report should not hide it */
+                        if let Err(e) = self.write_reports(&events.results) {
+                            UI.warning(&e);
+                        }
+                        Ok(events.results)
+                    },
+                    false => Err(format!("ansible-playbook exited with status {}: {}", s.trim(),
+                                         std::fs::read_to_string(self.path(STDERR_FILE)).unwrap_or_default().trim_end()))
+                },
+                (None, false) => {
+                    Self::clean_up(&self.dir);
+                    return Err("the run was interrupted before completing".to_string())
+                },
+                (None, true) => std::thread::sleep(POLL_INTERVAL)
+            }
+        }
+    }
+
+    /// Relays the output of the after-run hooks as the supervisor runs them, returning their failures
+    pub fn follow_hooks<F: FnMut(&str, &str)>(&self, mut on_line: F) -> Vec<(String, String)> {
+        let mut failures = Vec::new();
+        if !self.path(HOOKS_FILE).exists() {
+            return failures;
+        }
+
+        let mut log = None;
+        let mut buffer = Vec::new();
+        loop {
+            /* Checked before reading so that nothing logged before exiting is missed */
+            let alive = Self::pid_is_alive(&self.path(SUPERVISOR_PID_FILE));
+
+            if log.is_none() {
+                log = std::fs::File::open(self.path(HOOKS_LOG_FILE)).ok();
+            }
+            if let Some(l) = log.as_mut() {
+                l.read_to_end(&mut buffer).ok();
+            }
+
+            while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                let entry = serde_json::from_slice::<Json>(&buffer[..end]).unwrap_or_default();
+                buffer.drain(..=end);
+
+                match (entry["hook"].as_str(), entry["line"].as_str(), entry["error"].as_str()) {
+                    (Some(h), Some(l), _) => on_line(h, l),
+                    (Some(h), None, Some(e)) => failures.push((h.to_string(), e.to_string())),
+                    _ if entry["done"].as_bool() == Some(true) => return failures,
+                    _ => ()
+                }
+            }
+
+            match alive {
+                true => std::thread::sleep(POLL_INTERVAL),
+                false => return failures
+            }
+        }
+    }
+
+    /// Writes reports of the results in every format to the run directory
+    fn write_reports(&self, result: &AnsibleResult) -> Result<(), String> {
+        for format in ReportFormat::ALL.iter() {
+            std::fs::write(self.path(format.file_name()), format.render(result, &self.id))
+                .map_err(|e| format!("failed to write the {} report: {}", format.name(), e))?;
+        }
+        Ok(())
+    }
+
+    /// Stores an ansible-playbook invocation for the supervisor
+    fn serialise_command(command: &AnsibleCommand) -> Json {
+        json!({
+            "program": command.program,
+            "args": command.args,
+            "env": command.env,
+            "working_dir": command.working_dir.to_str().unwrap()
+        })
+    }
+
+    /// Reads back an ansible-playbook invocation
+    fn deserialise_command(json: &Json) -> Result<AnsibleCommand, String> {
+        let string = |v: &Json| v.as_str().map(String::from).ok_or("invalid run command".to_string());
+        Ok(AnsibleCommand {
+            program: string(&json["program"])?,
+            args: json["args"].as_array().ok_or("invalid run command")?.iter()
+                .map(string).collect::<Result<Vec<String>, String>>()?,
+            env: json["env"].as_object().ok_or("invalid run command")?.iter()
+                .map(|(k, v)| Ok((k.clone(), string(v)?))).collect::<Result<HashMap<String, String>, String>>()?,
+            working_dir: PathBuf::from(string(&json["working_dir"])?)
+        })
+    }
+}
+
+
+#[cfg(test)]
+#[cfg(not(tarpaulin_include))]
+mod tests {
+    use super::*;
+
+    fn settings(dir: &Path) -> RunSettings {
+        RunSettings { directory: dir.to_path_buf(), detach: false }
+    }
+
+    #[test]
+    fn command_round_trip() -> Result<(), String> {
+        let command = AnsibleCommand {
+            program: "ansible-playbook".to_string(),
+            args: vec!["-i".to_string(), "inventory".to_string()],
+            env: HashMap::from([("A".to_string(), "B".to_string())]),
+            working_dir: PathBuf::from("/tmp")
+        };
+
+        let read = Run::deserialise_command(&Run::serialise_command(&command))?;
+        match read.program == command.program && read.args == command.args
+            && read.env == command.env && read.working_dir == command.working_dir {
+            true => Ok(()),
+            false => Err("command changed through serialisation".to_string())
+        }
+    }
+
+    #[test]
+    fn open_invalid_id_ko() -> Result<(), String> {
+        match Run::open(&settings(Path::new("/tmp")), "../etc") {
+            Ok(_) => Err("opened a run outside of the runs directory".to_string()),
+            Err(_) => Ok(())
+        }
+    }
+
+    #[test]
+    fn follow_completed_run() -> Result<(), String> {
+        let dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let run = Run::create(&settings(dir.path()))?;
+        let reopened = Run::open(&settings(dir.path()), &run.id)?;
+
+        std::fs::write(run.path(JOURNAL_FILE), concat!(
+            r#"{"_event":"v2_runner_on_ok","task":{"name":"task"},"hosts":{"provisionee":{}}}"#, "\n",
+            r#"{"_event":"v2_playbook_on_stats","stats":{}}"#, "\n"
+        )).map_err(|e| e.to_string())?;
+        std::fs::write(run.path(STATUS_FILE), "0").map_err(|e| e.to_string())?;
+
+        let mut seen = 0;
+        let result = reopened.follow(|i, _| seen = i)?;
+        match (result.len(), seen, run.path("report.xml").is_file() && run.path("report.md").is_file()) {
+            (1, 1, true) => Ok(()),
+            _ => Err(format!("followed {} tasks, reported {}", result.len(), seen))
+        }
+    }
+
+    #[test]
+    fn follow_dead_run() -> Result<(), String> {
+        let dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let run = Run::create(&settings(dir.path()))?;
+        std::fs::write(run.path(KEY_FILE), "key").map_err(|e| e.to_string())?;
+
+        match (run.follow(|_, _| ()), run.path(KEY_FILE).exists()) {
+            (Err(_), false) => Ok(()),
+            r => Err(format!("unexpected outcome for a run which never started: {:?}", r.1))
+        }
+    }
+
+    #[test]
+    fn follow_failed_run() -> Result<(), String> {
+        let dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let run = Run::create(&settings(dir.path()))?;
+
+        std::fs::write(run.path(STDERR_FILE), "syntax error").map_err(|e| e.to_string())?;
+        std::fs::write(run.path(STATUS_FILE), "4").map_err(|e| e.to_string())?;
+
+        match run.follow(|_, _| ()) {
+            Ok(_) => Err("followed a run which never completed".to_string()),
+            Err(e) => match e.contains("syntax error") {
+                true => Ok(()),
+                false => Err(format!("unexpected error: {}", e))
+            }
+        }
+    }
+
+    #[test]
+    fn hooks_followed() -> Result<(), String> {
+        let dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let run = Run::create(&settings(dir.path()))?;
+        let yaml = yaml_rust::YamlLoader::load_from_str(
+            "{post_provision: {command: \"echo $SMU_SOURCE $SMU_RESULT\", stream: true}, on_failure: \"exit 3\"}").unwrap();
+        let variables = HashMap::from([("SMU_SOURCE".to_string(), "dotfiles".to_string())]);
+        run.write_hooks(&Hooks::parse(&yaml[0])?, &std::env::temp_dir(), &variables)?;
+
+        /* As the supervisor would once the run is over */
+        run.log_hooks(false);
+
+        let mut lines = Vec::new();
+        let failures = run.follow_hooks(|hook, line| lines.push(format!("{}: {}", hook, line)));
+        match (lines.as_slice(), failures.as_slice()) {
+            ([l], [(h, e)]) if l == "post_provision: dotfiles failure" && h == "on_failure" && e == "command failed with exit status: 3" => Ok(()),
+            r => Err(format!("unexpected hook output and failures: {:?}", r))
+        }
+    }
+}
@@ -0,0 +1,141 @@
+/* Set Me Up, a minimalistic Ansible-based remote provisioning tool
+ * Copyright (C) 2021 Julien JPK (jjpk.me)
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. */
+
+
+//! The interactive part of a session: each prompt is a step the client can go back from
+
+
+use crate::config::Config;
+use crate::provision::Provision;
+use crate::setup::{Setup, SSHAuthentication, SSHCredentials};
+use crate::sources::Source;
+use crate::ui::UI;
+use crate::ansible::ExtraVars;
+
+use std::collections::HashMap;
+
+use osshkeys::KeyPair;
+
+
+/// Session steps, in order
+enum Step<'a> {
+    /// Picks a source, or a playbook among all of them with all_playbooks set
+    Source,
+    Playbook(&'a Source),
+    Port(Provision<'a>),
+    Credentials(Provision<'a>, u16),
+    Confirm(Provision<'a>, u16, SSHCredentials)
+}
+
+/// Everything needed to start provisioning, as confirmed by the client
+pub struct Session<'a> {
+    pub provision: Provision<'a>,
+    pub setup: Setup
+}
+
+#[cfg(not(tarpaulin_include))]
+impl<'a> Session<'a> {
+    /// Walks the client through the prompts until they confirm
+    pub fn prompt(config: &'a Config) -> Result<Self, String> {
+        let mut step = Step::Source;
+
+        /* Kept until another source is picked, so that the client does not have to authorise a new key */
+        let mut keypair: Option<KeyPair> = None;
+
+        /* Going back to the sources neither updates them again nor offers a choice the client did not have */
+        let mut updated = HashMap::new();
+        let mut source_picked = false;
+
+        loop {
+            step = match step {
+                Step::Source => {
+                    keypair = None;
+                    match config.all_playbooks {
+                        true => Step::Port(Provision::prompt_any_playbook(config, &mut updated)?),
+                        false => {
+                            let (source, picked) = Provision::prompt_source(config, &mut updated)?;
+                            source_picked = picked;
+                            Step::Playbook(source)
+                        }
+                    }
+                },
+
+                Step::Playbook(source) => match Provision::prompt_playbook(source, source_picked)? {
+                    Some(p) => Step::Port(p),
+                    None => Step::Source
+                },
+
+                Step::Port(provision) => {
+                    /* Going back skips whatever was picked without asking */
+                    let previous = match (provision.picked, config.all_playbooks) {
+                        (true, false) => Some(Step::Playbook(provision.source)),
+                        (true, true) => Some(Step::Source),
+                        (false, false) if source_picked => Some(Step::Source),
+                        _ => None
+                    };
+
+                    match (Setup::prompt_port(previous.is_some()), previous) {
+                        (Some(port), _) => Step::Credentials(provision, port),
+                        (None, Some(previous)) => previous,
+                        (None, None) => Step::Port(provision)
+                    }
+                },
+
+                Step::Credentials(provision, port) => match Setup::prompt_credentials(config, provision.source, port, &mut keypair)? {
+                    Some(credentials) => Step::Confirm(provision, port, credentials),
+                    None => Step::Port(provision)
+                },
+
+                Step::Confirm(provision, port, credentials) => {
+                    let mut items = vec![
+                        ("Source".to_string(), provision.source.name.clone()),
+                        ("Playbook".to_string(), provision.playbook_path.to_str().unwrap().to_string()),
+                        ("Tags".to_string(), match provision.info.tags.is_empty() {
+                            true => "none".to_string(),
+                            false => provision.info.tags.join(", ")
+                        }),
+                        ("Port".to_string(), port.to_string()),
+                        ("Username".to_string(), credentials.username.clone()),
+                        ("Authentication".to_string(), credentials.authentication.describe().to_string())
+                    ];
+                    if !provision.source.ansible.extra_vars.is_empty() {
+                        items.push(("Extra variables".to_string(), provision.source.ansible.extra_vars.iter().map(|v| match v {
+                            ExtraVars::Inline(v) => v.to_string(),
+                            ExtraVars::File(f) => format!("@{}", f)
+                        }).collect::<Vec<String>>().join(" ")));
+                    }
+                    if !provision.source.ansible.args.is_empty() {
+                        items.push(("Arguments".to_string(), provision.source.ansible.args.join(" ")));
+                    }
+                    UI.summary("About to provision your machine with:", &items);
+
+                    match UI.confirm("Start provisioning? Type \"no\" to go back:") {
+                        true => return Ok(Self { provision, setup: Setup { reverse_port: port, credentials } }),
+                        false => {
+                            /* The key the client may have authorised is offered again for the next username */
+                            if let SSHAuthentication::Key(k, _) = credentials.authentication {
+                                keypair = Some(k);
+                            }
+                            Step::Credentials(provision, port)
+                        }
+                    }
+                }
+            };
+
+            UI.next_step();
+        }
+    }
+}
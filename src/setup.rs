@@ -15,21 +15,45 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>. */
 
 
-//! Prompts for the reverse port, the username and sets up key-based authentication
+//! Prompts for the reverse port, the username and sets up agent or key-based authentication
 
 
+use crate::config::Config;
+use crate::certificates::CertificateAuthority;
+use crate::keys::{KeySettings, AuthorizedKeyOptions};
+use crate::sources::Source;
 use crate::ui::UI;
 
 use std::net::TcpListener;
 
-use osshkeys::{KeyPair, KeyType};
+use osshkeys::KeyPair;
+use osshkeys::cipher::Cipher;
 use ssh2::Session;
 
 
-/// SSH credentials to the client: user and key pair
+/// How SetMeUp authenticates with the client
+pub enum SSHAuthentication {
+    /// Through the client's forwarded SSH agent
+    Agent,
+    /// Through a generated key pair, with an optional CA-signed certificate
+    Key(KeyPair, Option<String>)
+}
+
+impl SSHAuthentication {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Self::Agent => "forwarded SSH agent",
+            Self::Key(_, Some(_)) => "generated key with a CA certificate",
+            Self::Key(_, None) => "generated key"
+        }
+    }
+}
+
+/// SSH credentials to the client: user, authentication method and the public key to clean up afterwards
 pub struct SSHCredentials {
     pub username: String,
-    pub keypair: KeyPair
+    pub authentication: SSHAuthentication,
+    pub cleanup_key: Option<String>
 }
 
 /// Client setup parameters: a port number and credentials
@@ -40,30 +64,35 @@ pub struct Setup {
 
 #[cfg(not(tarpaulin_include))]
 impl Setup {
-    /// Prompts the client for a port and credentials
-    pub fn prompt() -> Result<Self, String> {
-        let reverse_port = Self::prompt_port()?;
-        let credentials = Self::key_setup(reverse_port)?;
-        Ok(Self { reverse_port, credentials })
+    /// Prompts the client for credentials to provision from the given source through the given port, or None to go back
+    ///
+    /// The keypair is generated into `keypair` unless there already is one, and taken from it if the credentials use it.
+    pub fn prompt_credentials(config: &Config, source: &Source, reverse_port: u16,
+                              keypair: &mut Option<KeyPair>) -> Result<Option<SSHCredentials>, String> {
+        let key_settings = source.key.unwrap_or(config.key);
+        Self::key_setup(reverse_port, keypair, &key_settings, &config.authorized_keys, config.certificate_authority.as_ref())
     }
 
     /// Checks if a client is locally bound
     fn port_is_bound(port: u16) -> bool {
         match TcpListener::bind(("127.0.0.1", port)) {
             Ok(_) => false,
-            Err(e) => match e.kind() {
-                std::io::ErrorKind::AddrInUse => true,
-                _ => false
-            }
+            Err(e) => e.kind() == std::io::ErrorKind::AddrInUse
         }
     }
 
-    /// Prompts the client for the reverse forward port
-    fn prompt_port() -> Result<u16, String> {
+    /// Prompts the client for the reverse forward port, or None to go back if allowed
+    pub fn prompt_port(back: bool) -> Option<u16> {
+        let message = "Which port did you pick for remote forwarding?";
         loop {
-            match UI.prompt("Which port did you pick for remote forwarding?").parse::<u16>() {
+            let answer = match back {
+                true => UI.prompt_or_back(message)?,
+                false => UI.prompt(message)
+            };
+
+            match answer.parse::<u16>() {
                 Ok(p) => match Self::port_is_bound(p) {
-                    true => return Ok(p),
+                    true => return Some(p),
                     false => UI.error(&format!("Port is not bound locally: {}", p))
                 }
                 Err(e) => UI.error(&format!("Invalid port specification: {}", e))
@@ -71,21 +100,29 @@ impl Setup {
         }
     }
 
-    /// Attempts to connect via SSH back to the client to check credentials
-    pub fn test_credentials(local_port: u16, username: &String, keypair: &KeyPair) -> Result<(), String> {
+    /// Opens an SSH session to the client through the reverse forward port
+    fn open_session(local_port: u16) -> Result<Session, String> {
         let tcp = std::net::TcpStream::connect(format!("127.0.0.1:{}", local_port))
             .map_err(|e| format!("failed to connect via local port {}: {}", local_port, e))?;
         let mut session = Session::new().map_err(|e| format!("failed to open session: {}", e))?;
         session.set_tcp_stream(tcp);
         session.handshake().map_err(|e| format!("handshake failed: {}", e))?;
+        Ok(session)
+    }
 
-        let pem_privkey = keypair.serialize_pem(None)
+    /// Attempts to connect via SSH back to the client to check credentials
+    pub fn test_credentials(local_port: u16, username: &str, keypair: &KeyPair,
+                            certificate: Option<&str>) -> Result<(), String> {
+        let session = Self::open_session(local_port)?;
+
+        /* Unlike PEM, the OpenSSH format is available for every key type */
+        let privkey = keypair.serialize_openssh(None, Cipher::Null)
             .map_err(|e| format!("failed to encode private key: {}", e))?;
 
         let result = session.userauth_pubkey_memory(
             username,
-            None,
-            &pem_privkey,
+            certificate,
+            &privkey,
             None
         ).map(|_| ()).map_err(|e| format!("{}", e));
 
@@ -93,26 +130,79 @@ impl Setup {
         result
     }
 
-    /// Prompts the client for a username and checks the key setup
-    fn key_setup(port: u16) -> Result<SSHCredentials, String> {
-        let keypair = KeyPair::generate(KeyType::ECDSA, 0).map_err(|e| format!("failed to generate keypair: {}", e))?;
+    /// Attempts to connect via SSH back to the client using the forwarded agent
+    pub fn test_agent(local_port: u16, username: &str) -> Result<(), String> {
+        let session = Self::open_session(local_port)?;
+        let result = session.userauth_agent(username).map_err(|e| format!("{}", e));
+        session.disconnect(None, "setmeup authentication test complete", None).ok();
+        result
+    }
+
+    /// Prompts the client for a username and checks the key setup, or returns None to go back
+    fn key_setup(port: u16, slot: &mut Option<KeyPair>, key_settings: &KeySettings, authorized_keys: &AuthorizedKeyOptions,
+                 ca: Option<&CertificateAuthority>) -> Result<Option<SSHCredentials>, String> {
+        if slot.is_none() {
+            *slot = Some(key_settings.generate()?);
+        }
+        let keypair = slot.as_ref().unwrap();
         let keypair_str = keypair.serialize_publickey().map_err(|e| format!("failed to serialise keypair: {}", e))?.to_string();
 
         let mut username = String::new();
 
         loop {
             while username.is_empty() {
-                username = UI.prompt("Which username should SetMeUp use to reach you over SSH?");
+                username = match UI.prompt_or_back("Which username should SetMeUp use to reach you over SSH?") {
+                    Some(u) => u,
+                    None => return Ok(None)
+                };
                 if username.is_empty() {
-                    UI.error(&format!("The username cannot be empty"));
+                    UI.error("The username cannot be empty");
+                } else if username.chars().any(|c| c.is_whitespace() || c.is_control() || c == '=') {
+                    UI.error("The username cannot contain spaces, control characters or \"=\"");
+                    username.clear();
+                }
+            }
+
+            /* The client's own key, through their forwarded agent, is often already authorised */
+            if std::env::var_os("SSH_AUTH_SOCK").is_some() {
+                match Self::test_agent(port, &username) {
+                    Ok(_) => return Ok(Some(SSHCredentials {
+                        username,
+                        authentication: SSHAuthentication::Agent,
+                        cleanup_key: None
+                    })),
+                    Err(e) => UI.error(&format!("Agent authentication failed, falling back to a generated key: {}", e))
+                }
+            }
+
+            /* With a CA, machines trusting it should let us in without any manual step */
+            if let Some(ca) = ca {
+                match ca.sign(keypair, &username)
+                    .and_then(|c| Self::test_credentials(port, &username, keypair, Some(&c)).map(|_| c)) {
+                    Ok(certificate) => return Ok(Some(SSHCredentials {
+                        username,
+                        authentication: SSHAuthentication::Key(slot.take().unwrap(), Some(certificate)),
+                        cleanup_key: None
+                    })),
+                    Err(e) => UI.error(&format!("Certificate authentication failed, falling back to a public key: {}", e))
                 }
             }
 
-            UI.present_pubkey(&username, &keypair_str);
-            UI.prompt("Press the Enter key where you are done:");
+            UI.present_pubkey(&username, &key_settings.algorithm(), &authorized_keys.snippet(&keypair_str));
+            if !UI.confirm("Press the Enter key when you are done, or type \"no\" to pick another username:") {
+                username.clear();
+                continue;
+            }
 
-            match Self::test_credentials(port, &username, &keypair) {
-                Ok(_) => return Ok(SSHCredentials { username, keypair }),
+            match Self::test_credentials(port, &username, keypair, None) {
+                Ok(_) => return Ok(Some(SSHCredentials {
+                    username,
+                    authentication: SSHAuthentication::Key(slot.take().unwrap(), None),
+                    cleanup_key: match authorized_keys.cleanup {
+                        true => Some(keypair_str),
+                        false => None
+                    }
+                })),
                 Err(e) => {
                     UI.error(&format!("Authentication test failed: {}", e));
                     username.clear();
@@ -0,0 +1,165 @@
+/* Set Me Up, a minimalistic Ansible-based remote provisioning tool
+ * Copyright (C) 2021 Julien JPK (jjpk.me)
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. */
+
+
+//! Clean shutdown on signals and fatal errors
+
+
+use crate::audit;
+use crate::snapshot;
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+
+/// Exit status used when the session is interrupted by a signal
+pub const EXIT_INTERRUPTED: i32 = 3;
+
+/// What needs cleaning up before exiting: temporary files and running child process groups
+struct State {
+    paths: Vec<PathBuf>,
+    child_groups: Vec<i32>
+}
+
+lazy_static! {
+    static ref STATE: Mutex<State> = Mutex::new(State { paths: Vec::new(), child_groups: Vec::new() });
+}
+
+/// Registers a path for removal on shutdown for as long as it is alive
+pub struct TrackedPath(PathBuf);
+
+impl TrackedPath {
+    pub fn new(path: &Path) -> Self {
+        if let Ok(mut s) = STATE.lock() {
+            s.paths.push(path.to_path_buf());
+        }
+        Self(path.to_path_buf())
+    }
+}
+
+impl Drop for TrackedPath {
+    fn drop(&mut self) {
+        if let Ok(mut s) = STATE.lock() {
+            s.paths.retain(|p| p != &self.0);
+        }
+    }
+}
+
+/// Registers a child process group to kill on shutdown for as long as it is alive
+pub struct TrackedChild(i32);
+
+impl TrackedChild {
+    pub fn new(pid: u32) -> Self {
+        if let Ok(mut s) = STATE.lock() {
+            s.child_groups.push(pid as i32);
+        }
+        Self(pid as i32)
+    }
+}
+
+impl Drop for TrackedChild {
+    fn drop(&mut self) {
+        if let Ok(mut s) = STATE.lock() {
+            s.child_groups.retain(|g| *g != self.0);
+        }
+    }
+}
+
+/// Kills child process groups and removes temporary files
+fn cleanup() {
+    let mut state = match STATE.lock() {
+        Ok(s) => s,
+        Err(e) => e.into_inner()
+    };
+
+    for group in state.child_groups.drain(..) {
+        unsafe { libc::killpg(group, libc::SIGTERM); }
+    }
+
+    /* Directories are source snapshots, which may be registered worktrees */
+    for path in state.paths.drain(..) {
+        match path.is_dir() {
+            true => snapshot::remove(&path),
+            false => { std::fs::remove_file(&path).ok(); }
+        };
+    }
+}
+
+/// Cleans up and exits with the given status
+pub fn exit(status: i32) -> ! {
+    cleanup();
+    std::process::exit(status);
+}
+
+/// Cleans up and exits after the session was interrupted
+pub fn interrupt(cause: &str, notify: bool) -> ! {
+    if notify {
+        writeln!(std::io::stderr(), "\nInterrupted, cleaning up...").ok();
+    }
+
+    audit::log(&format!("session interrupted by {}", cause));
+    exit(EXIT_INTERRUPTED);
+}
+
+/// Starts a thread handling SIGHUP, SIGINT and SIGTERM
+#[cfg(not(tarpaulin_include))]
+pub fn install() -> Result<(), String> {
+    let mut signals = Signals::new([SIGHUP, SIGINT, SIGTERM]).map_err(|e| format!("failed to set up signal handling: {}", e))?;
+
+    std::thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            let name = match signal {
+                SIGHUP => "SIGHUP",
+                SIGINT => "SIGINT",
+                _ => "SIGTERM"
+            };
+
+            /* After a hangup, there is nobody left to read this */
+            interrupt(name, signal != SIGHUP);
+        }
+    });
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+#[cfg(not(tarpaulin_include))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracked_path_released() -> Result<(), String> {
+        let dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+
+        {
+            let _tracked = TrackedPath::new(dir.path());
+            if !STATE.lock().unwrap().paths.contains(&dir.path().to_path_buf()) {
+                return Err("path was not tracked".to_string())
+            }
+        }
+
+        match STATE.lock().unwrap().paths.contains(&dir.path().to_path_buf()) {
+            true => Err("path still tracked after being dropped".to_string()),
+            false => Ok(())
+        }
+    }
+}
@@ -0,0 +1,163 @@
+/* Set Me Up, a minimalistic Ansible-based remote provisioning tool
+ * Copyright (C) 2021 Julien JPK (jjpk.me)
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. */
+
+
+//! Source snapshots, keeping running plays out of reach of other sessions' updates
+
+
+use crate::exec;
+
+use std::path::{Component, Path};
+
+
+/// How a source is snapshotted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SnapshotMode {
+    /// Copies the source's files, leaving out its .git directory
+    Copy,
+    /// Checks the source's HEAD out in a detached git worktree, without untracked or ignored files
+    Worktree
+}
+
+impl SnapshotMode {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "copy" => Ok(Self::Copy),
+            "worktree" => Ok(Self::Worktree),
+            _ => Err(format!("unsupported snapshot mode {} (expected copy or worktree)", name))
+        }
+    }
+}
+
+
+/// Snapshots a source directory to the given path
+pub fn take(mode: SnapshotMode, source: &Path, destination: &Path) -> Result<(), String> {
+    match mode {
+        SnapshotMode::Copy => std::fs::canonicalize(source).and_then(|s| copy_tree(&s, destination, 0)).map_err(|e| e.to_string()),
+        SnapshotMode::Worktree => {
+            /* Forgets the worktrees of runs which could not remove theirs */
+            exec::run("git", vec!["worktree", "prune"], source, None).ok();
+            exec::run("git", vec!["worktree", "add", "--detach", destination.to_str().unwrap(), "HEAD"], source, None).map(|_| ())
+        }
+    }.map_err(|e| format!("failed to snapshot the source: {}", e.trim()))
+}
+
+/// Removes a snapshot, unregistering it from the source repository if it is a worktree
+pub fn remove(path: &Path) {
+    if path.join(".git").is_file() {
+        exec::run("git", vec!["worktree", "remove", "--force", path.to_str().unwrap()], path, None).ok();
+    }
+    std::fs::remove_dir_all(path).ok();
+}
+
+/// Whether a relative link target, found `depth` directories below the source's root, leads out of the source
+fn escapes(target: &Path, mut depth: usize) -> bool {
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(d) => depth = d,
+                None => return true
+            },
+            _ => ()
+        }
+    }
+    false
+}
+
+/// Copies a directory recursively, recreating symbolic links rather than following them
+///
+/// Relative links leading out of the source are made absolute, so that they still point to the same files from the copy.
+fn copy_tree(from: &Path, to: &Path, depth: usize) -> std::io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let (source, destination) = (entry.path(), to.join(entry.file_name()));
+        let file_type = entry.file_type()?;
+
+        if file_type.is_symlink() {
+            let target = std::fs::read_link(&source)?;
+            match target.is_relative() && escapes(&target, depth) {
+                true => std::os::unix::fs::symlink(from.join(target), &destination)?,
+                false => std::os::unix::fs::symlink(target, &destination)?
+            }
+        } else if file_type.is_dir() {
+            if entry.file_name() != ".git" {
+                copy_tree(&source, &destination, depth + 1)?;
+            }
+        } else {
+            std::fs::copy(&source, &destination)?;
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+#[cfg(not(tarpaulin_include))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copied() -> Result<(), String> {
+        let base = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let source = base.path().join("source");
+        let destination = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let snapshot = destination.path().join("source");
+
+        std::fs::create_dir_all(source.join("roles/web")).map_err(|e| e.to_string())?;
+        std::fs::create_dir_all(source.join(".git")).map_err(|e| e.to_string())?;
+        std::fs::write(source.join("roles/web/main.yml"), "- hosts: all").map_err(|e| e.to_string())?;
+        std::fs::write(base.path().join("outside"), "shared").map_err(|e| e.to_string())?;
+        std::os::unix::fs::symlink("roles/web/main.yml", source.join("site.yml")).map_err(|e| e.to_string())?;
+        std::os::unix::fs::symlink("../../../outside", source.join("roles/web/shared")).map_err(|e| e.to_string())?;
+
+        take(SnapshotMode::Copy, &source, &snapshot)?;
+        let copied = (
+            std::fs::read_to_string(snapshot.join("site.yml")).ok(),
+            std::fs::read_link(snapshot.join("site.yml")).ok(),
+            std::fs::read_to_string(snapshot.join("roles/web/shared")).ok(),
+            snapshot.join(".git").exists()
+        );
+
+        remove(&snapshot);
+        match (copied, snapshot.exists()) {
+            ((Some(c), Some(l), Some(s), false), false)
+                if c == "- hosts: all" && l == Path::new("roles/web/main.yml") && s == "shared" => Ok(()),
+            r => Err(format!("unexpected snapshot: {:?}", r))
+        }
+    }
+
+    #[test]
+    fn escaping_links() -> Result<(), String> {
+        let cases = [("../outside", 0, true), ("../site.yml", 1, false), ("../../outside", 1, true),
+                     ("roles/../../outside", 0, true), ("./site.yml", 0, false)];
+        for (target, depth, expected) in cases.iter() {
+            if escapes(Path::new(target), *depth) != *expected {
+                return Err(format!("expected {} for {} at depth {}", expected, target, depth))
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn mode_from_name() -> Result<(), String> {
+        match (SnapshotMode::from_name("copy"), SnapshotMode::from_name("worktree"), SnapshotMode::from_name("rsync")) {
+            (Ok(SnapshotMode::Copy), Ok(SnapshotMode::Worktree), Err(_)) => Ok(()),
+            _ => Err("failed to parse snapshot mode names".to_string())
+        }
+    }
+}
@@ -18,15 +18,25 @@
 //! Parsing and updating logic for playbook sources
 
 
-use crate::exec;
 use crate::ansible::AnsibleContext;
-
+use crate::keys::KeySettings;
+use crate::playbooks::{self, PlaybookInfo};
+use crate::patterns::{PathPatterns, IGNORE_FILE};
+use crate::discovery::{self, DiscoveryCache, DiscoverySettings};
+use crate::snapshot::SnapshotMode;
+use crate::updates::{UpdateLock, UpdatePolicy};
+use crate::hooks::{Hook, Hooks};
+use crate::ui::UI;
+
+use std::collections::BTreeMap;
 use std::fmt::Display;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
 
 use regex::Regex;
 use faccess::PathExt;
-use walkdir::WalkDir;
+use ignore::{WalkBuilder, WalkState};
 use yaml_rust::Yaml;
 
 
@@ -35,26 +45,64 @@ pub struct Source {
     pub name: String,
     pub path: PathBuf,
     pub recurse: bool,
+    /// Overrides recurse, 1 being the source's root alone
+    pub max_depth: Option<usize>,
+    pub follow_links: bool,
+    pub skip_hidden: bool,
+    /// Names of the directories never explored
+    pub skip_dirs: Vec<String>,
     pub playbook_match: Regex,
-    pub pre_provision: Option<String>,
-    pub ansible: AnsibleContext
+    pub include: PathPatterns,
+    pub exclude: PathPatterns,
+    pub check_content: bool,
+    pub hooks: Hooks,
+    /// Skips pre_provision when the last successful run is more recent
+    pub pre_provision_interval: Option<Duration>,
+    pub on_update_failure: UpdatePolicy,
+    /// Runs Ansible from a snapshot of the source rather than from the source itself
+    pub snapshot: Option<SnapshotMode>,
+    pub ansible: AnsibleContext,
+    pub key: Option<KeySettings>,
+    /// Where discovery results are cached, if anywhere
+    pub cache: Option<PathBuf>,
+    /// Identifies the source's configuration in its cache
+    pub fingerprint: String,
+    /// Locked while the source is updated, and holding the time of the last update
+    pub state: Option<PathBuf>
 }
 
 const DEFAULT_MATCH: &str = r#"\.ya?ml$"#;
 
 impl Source {
-    /// Creates a new Source object explicitely
+    /// Creates a new Source object explicitely, with defaults for the exploration and update parameters
+    #[cfg(test)]
     fn new(name: String, path: PathBuf, recurse: bool,
            playbook_match: Regex, pre_provision: Option<String>,
            ansible: AnsibleContext) -> Self {
-        Self { name, path, recurse, playbook_match, pre_provision, ansible }
+        Self { name, path, recurse, max_depth: None, follow_links: false, skip_hidden: false, skip_dirs: Vec::new(),
+               playbook_match, include: PathPatterns::default(),
+               exclude: PathPatterns::default(), check_content: false,
+               hooks: Hooks { pre_provision: pre_provision.map(Hook::new), ..Hooks::default() }, pre_provision_interval: None,
+               on_update_failure: UpdatePolicy::Fail, snapshot: None,
+               ansible, key: None, cache: None, fingerprint: String::new(), state: None }
     }
 
     /// Parses YAML for a playbook source
-    pub fn parse(name: String, yaml: &Yaml) -> Result<Self, String> {
-        Ok(Self::new(
-            name,
-            match &yaml["path"] {
+    pub fn parse(name: String, yaml: &Yaml, discovery: &DiscoverySettings) -> Result<Self, String> {
+        let max_depth = match yaml["max_depth"] {
+            Yaml::Integer(i) if i > 0 => Some(i as usize),
+            Yaml::BadValue => None,
+            _ => return Err("expected positive integer for the max_depth source parameter".to_string())
+        };
+
+        let pre_provision = match &yaml["pre_provision"] {
+            Yaml::String(s) => Some(Hook::new(s.clone())),
+            Yaml::BadValue => None,
+            _ => return Err("expected string for the pre_provision source parameter".to_string())
+        };
+
+        Ok(Self {
+            path: match &yaml["path"] {
                 Yaml::String(s) => {
                     let path = PathBuf::from(s);
                     match path.is_dir() && path.readable() {
@@ -66,14 +114,37 @@ impl Source {
                 _ => return Err("expected string for the path parameter".to_string())
             },
 
-            match yaml["recurse"] {
+            recurse: match yaml["recurse"] {
                 Yaml::Boolean(b) => b,
                 Yaml::BadValue => false,
                 _ => return Err("expected boolean for the recurse source parameter".to_string())
+            } || max_depth.is_some_and(|d| d > 1),
+
+            max_depth,
+
+            follow_links: match yaml["follow_links"] {
+                Yaml::Boolean(b) => b,
+                Yaml::BadValue => false,
+                _ => return Err("expected boolean for the follow_links source parameter".to_string())
             },
 
-            match &yaml["playbook_match"] {
-                Yaml::String(s) => match Regex::new(&s) {
+            skip_hidden: match yaml["skip_hidden"] {
+                Yaml::Boolean(b) => b,
+                Yaml::BadValue => false,
+                _ => return Err("expected boolean for the skip_hidden source parameter".to_string())
+            },
+
+            skip_dirs: match &yaml["skip_dirs"] {
+                Yaml::Array(a) => a.iter().map(|d| match d {
+                    Yaml::String(s) => Ok(s.clone()),
+                    _ => Err("expected list of strings for the skip_dirs source parameter".to_string())
+                }).collect::<Result<Vec<String>, String>>()?,
+                Yaml::BadValue => Vec::new(),
+                _ => return Err("expected list of strings for the skip_dirs source parameter".to_string())
+            },
+
+            playbook_match: match &yaml["playbook_match"] {
+                Yaml::String(s) => match Regex::new(s) {
                     Ok(r) => r,
                     Err(e) => return Err(e.to_string())
                 },
@@ -81,43 +152,234 @@ impl Source {
                 _ => return Err("expected string for the playbook_match source parameter".to_string())
             },
 
-            match &yaml["pre_provision"] {
-                Yaml::String(s) => Some(s.clone()),
+            include: PathPatterns::parse(&yaml["include"], "include")?,
+            exclude: PathPatterns::parse(&yaml["exclude"], "exclude")?,
+
+            check_content: match yaml["check_content"] {
+                Yaml::Boolean(b) => b,
+                Yaml::BadValue => false,
+                _ => return Err("expected boolean for the check_content source parameter".to_string())
+            },
+
+            hooks: match (pre_provision, Hooks::parse(&yaml["hooks"])?) {
+                (Some(_), Hooks { pre_provision: Some(_), .. }) =>
+                    return Err("pre_provision is set both as a source parameter and as a hook".to_string()),
+                (Some(p), hooks) => Hooks { pre_provision: Some(p), ..hooks },
+                (None, hooks) => hooks
+            },
+
+            pre_provision_interval: match yaml["pre_provision_interval"] {
+                Yaml::Integer(i) if i >= 0 => Some(Duration::from_secs(i as u64)),
                 Yaml::BadValue => None,
-                _ => return Err("expected string for the pre_provision source parameter".to_string())
+                _ => return Err("expected non-negative integer for the pre_provision_interval source parameter".to_string())
             },
 
-            match &yaml["ansible_playbook"].as_hash() {
-                Some(_) => match AnsibleContext::parse(&yaml["ansible_playbook"]) {
-                    Ok(a) => a,
-                    Err(e) => return Err(e)
-                },
+            on_update_failure: match &yaml["on_update_failure"] {
+                Yaml::String(s) => UpdatePolicy::from_name(s)?,
+                Yaml::BadValue => UpdatePolicy::Fail,
+                _ => return Err("expected string for the on_update_failure source parameter".to_string())
+            },
+
+            snapshot: match &yaml["snapshot"] {
+                Yaml::String(s) => Some(SnapshotMode::from_name(s)?),
+                Yaml::BadValue => None,
+                _ => return Err("expected string for the snapshot source parameter".to_string())
+            },
+
+            ansible: match &yaml["ansible_playbook"].as_hash() {
+                Some(_) => AnsibleContext::parse(&yaml["ansible_playbook"])?,
                 None => AnsibleContext::default()
+            },
+
+            key: match &yaml["key"] {
+                Yaml::Hash(_) => Some(KeySettings::parse(&yaml["key"])?),
+                Yaml::BadValue => None,
+                _ => return Err("expected dictionary for the key source parameter".to_string())
+            },
+
+            cache: discovery.cache_file(&name),
+            fingerprint: discovery::fingerprint(&name, yaml),
+            state: Some(discovery.state_file(&name)),
+            name
+        })
+    }
+
+    /// Checks whether a directory should be left unexplored given its name
+    fn skips_dir(&self, name: &str) -> bool {
+        (self.skip_hidden && name.starts_with('.')) || self.skip_dirs.iter().any(|d| d == name)
+    }
+
+    /// Runs the pre_provision hook for this source, one session at a time
+    ///
+    /// Returns how long ago the source was last updated if it was recent enough for the update to be skipped.
+    pub fn update(&self) -> Result<Option<Duration>, String> {
+        let hook = match &self.hooks.pre_provision {
+            Some(h) => h,
+            None => return Ok(None)
+        };
+
+        let mut lock = self.lock()?;
+        if let (Some(l), Some(interval)) = (lock.as_mut(), self.pre_provision_interval) {
+            match l.last_update() {
+                Some(age) if age < interval => return Ok(Some(age)),
+                _ => ()
             }
-        ))
+        }
+
+        hook.run(&self.path, &vec![("SMU_SOURCE".to_string(), self.name.clone())].into_iter().collect(),
+                 |line| UI.hook_output("pre_provision", line))?;
+        match lock.as_mut() {
+            Some(l) => l.record_update().map(|_| None),
+            None => Ok(None)
+        }
     }
 
-    /// Runs the pre_provision command for this source
-    pub fn update(&self) -> Result<(), String> {
-        match &self.pre_provision {
-            Some(c) => exec::shell(&c, self.path.as_path(), None).map(|_| ()),
-            None => Ok(())
+    /// Time since the last successful update, if known
+    pub fn last_update(&self) -> Option<Duration> {
+        self.lock().ok().flatten().and_then(|mut l| l.last_update())
+    }
+
+    /// Keeps other sessions from updating the source until the lock is dropped
+    pub fn lock(&self) -> Result<Option<UpdateLock>, String> {
+        self.state.as_deref().map(UpdateLock::acquire).transpose()
+    }
+
+    /// Locates this source's playbooks and reads what they tell about themselves, with warnings about the files which could not be checked
+    ///
+    /// Results come from the discovery cache for as long as the source is left unchanged.
+    pub fn explore(&self) -> DiscoveryCache {
+        if let Some(c) = self.cache.as_deref().and_then(DiscoveryCache::load) {
+            if c.is_fresh(&self.path, &self.fingerprint) {
+                return c;
+            }
+        }
+
+        let mut walked = self.walk();
+        if let Some(Err(e)) = self.cache.as_deref().map(|f| walked.save(f)) {
+            walked.warnings.push(e);
         }
+        walked
     }
 
-    /// Locates this source's playbooks
-    pub fn explore(&self) -> Vec<PathBuf> {
-        let walker = WalkDir::new(&self.path);
-        let walker = match self.recurse {
-            true => walker,
-            false => walker.max_depth(1)
-        };
+    /// Walks the source in parallel, matching paths relative to its root and leaving excluded directories unexplored
+    fn walk(&self) -> DiscoveryCache {
+        let mut warnings = Vec::new();
+        let mut mtimes = BTreeMap::new();
+        let ignored = PathPatterns::read_ignore_file(&self.path).unwrap_or_else(|e| {
+            warnings.push(e);
+            PathPatterns::default()
+        });
+        if let Some(m) = discovery::mtime(&self.path.join(IGNORE_FILE)) {
+            mtimes.insert(IGNORE_FILE.to_string(), m);
+        }
 
-        walker.into_iter()
-            .flatten()
-            .filter(|entry| self.playbook_match.is_match(entry.path().to_str().unwrap()))
-            .map(|entry| PathBuf::from(entry.path().strip_prefix(&self.path).unwrap()))
-            .collect()
+        /* Read before walking, so that anything changing during the walk makes the cache stale */
+        let head = discovery::git_head(&self.path);
+
+        let found = Mutex::new(Vec::new());
+        let warnings = Mutex::new(warnings);
+        let mtimes = Mutex::new(mtimes);
+
+        WalkBuilder::new(&self.path)
+            .standard_filters(false)
+            .follow_links(self.follow_links)
+            .max_depth(match (self.max_depth, self.recurse) {
+                (Some(d), _) => Some(d),
+                (None, true) => None,
+                (None, false) => Some(1)
+            })
+            .build_parallel()
+            .run(|| {
+                let (found, warnings, mtimes, ignored) = (&found, &warnings, &mtimes, &ignored);
+                Box::new(move |entry| {
+                    let entry = match entry {
+                        Ok(e) => e,
+                        Err(e) => {
+                            /* Symbolic link loops are reported, other unreadable entries silently left out */
+                            if let Some(child) = loop_child(&e) {
+                                warnings.lock().unwrap().push(format!("skipped symbolic link loop at {}",
+                                                                      child.strip_prefix(&self.path).unwrap_or(child).display()));
+                            }
+                            return WalkState::Continue;
+                        }
+                    };
+
+                    let path = entry.path().strip_prefix(&self.path).unwrap_or_else(|_| entry.path()).to_path_buf();
+                    let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+                    if entry.depth() > 0 && !self.explores(&path, is_dir, ignored) {
+                        return WalkState::Skip;
+                    }
+
+                    match is_dir {
+                        true => if let Some(m) = discovery::mtime(entry.path()) {
+                            mtimes.lock().unwrap().insert(path.to_string_lossy().into_owned(), m);
+                        },
+                        false => if self.is_candidate(&path) {
+                            /* Editing a file or a sidecar leaves its directory untouched, yet changes what is offered */
+                            let mut files = vec![entry.path().to_path_buf()];
+                            files.extend(playbooks::sidecars(entry.path()));
+                            for file in files {
+                                if let Some(m) = discovery::mtime(&file) {
+                                    mtimes.lock().unwrap().insert(file.strip_prefix(&self.path).unwrap_or(&file).to_string_lossy().into_owned(), m);
+                                }
+                            }
+
+                            if self.holds_plays(&path, warnings) {
+                                found.lock().unwrap().push((path, PlaybookInfo::read(entry.path())));
+                            }
+                        }
+                    }
+                    WalkState::Continue
+                })
+            });
+
+        let mut found = found.into_inner().unwrap();
+        let mut warnings = warnings.into_inner().unwrap();
+        found.sort_by(|(a, _), (b, _)| a.cmp(b));
+        warnings.sort();
+        let (playbooks, infos) = found.into_iter().unzip();
+
+        DiscoveryCache {
+            fingerprint: self.fingerprint.clone(),
+            head,
+            mtimes: mtimes.into_inner().unwrap(),
+            playbooks,
+            infos,
+            warnings
+        }
+    }
+
+    /// Checks whether an entry should be explored, given the skipped directories and exclusion patterns
+    fn explores(&self, path: &Path, is_dir: bool, ignored: &PathPatterns) -> bool {
+        let skipped = is_dir && self.skips_dir(path.file_name().and_then(|n| n.to_str()).unwrap_or(""));
+        !(skipped || self.exclude.matches(path, is_dir) || ignored.matches(path, is_dir))
+    }
+
+    /// Checks whether a file's path makes it a playbook candidate
+    fn is_candidate(&self, path: &Path) -> bool {
+        self.playbook_match.is_match(&path.to_string_lossy()) && !playbooks::is_sidecar(&self.path.join(path))
+            && (self.include.is_empty() || self.include.matches_within(path, false))
+    }
+
+    /// Checks the content of a candidate file if required, warning about those which cannot be parsed
+    fn holds_plays(&self, path: &Path, warnings: &Mutex<Vec<String>>) -> bool {
+        !self.check_content || match playbooks::is_playbook(&self.path.join(path)) {
+            Ok(p) => p,
+            Err(e) => {
+                warnings.lock().unwrap().push(format!("skipped {}: {}", path.display(), e));
+                false
+            }
+        }
+    }
+}
+
+/// The link making up a loop, however deep in the walk error
+fn loop_child(error: &ignore::Error) -> Option<&Path> {
+    match error {
+        ignore::Error::Loop { child, .. } => Some(child),
+        ignore::Error::WithPath { err, .. } | ignore::Error::WithDepth { err, .. }
+            | ignore::Error::WithLineNumber { err, .. } => loop_child(err),
+        _ => None
     }
 }
 
@@ -132,6 +394,7 @@ impl Display for Source {
 
 #[cfg(test)]
 #[cfg(not(tarpaulin_include))]
+#[allow(clippy::op_ref, clippy::needless_borrow)]
 mod tests {
     use super::*;
     use array_tool::vec::Intersect;
@@ -143,7 +406,7 @@ mod tests {
     }
 
     fn expect_playbooks(source: Source, expected: Vec<&str>) -> Result<(), String> {
-        let playbooks = source.explore();
+        let playbooks = source.explore().playbooks;
         let actual: Vec<&str> = playbooks.iter().filter_map(|p| p.to_str()).collect();
         let expected_len = expected.len();
 
@@ -160,7 +423,7 @@ mod tests {
                                     false,
                                     Regex::new(DEFAULT_MATCH).unwrap(),
                                     None,
-                                    AnsibleContext::default()).explore();
+                                    AnsibleContext::default()).explore().playbooks;
 
         match playbooks.len() {
             0 => Ok(()),
@@ -175,7 +438,7 @@ mod tests {
                                     false,
                                     Regex::new(DEFAULT_MATCH).unwrap(),
                                     None,
-                                    AnsibleContext::default()).explore();
+                                    AnsibleContext::default()).explore().playbooks;
 
         match playbooks.len() {
             0 => Ok(()),
@@ -249,6 +512,200 @@ mod tests {
         expect_playbooks(source, vec!["playbook1.yml", "depth2/depth1/playbook3.yml"])
     }
 
+    #[test]
+    fn sidecars_skipped() -> Result<(), String> {
+        let source = Source::new(String::from("playbooks"),
+                                 get_source_path("../playbooks"),
+                                 false,
+                                 Regex::new(DEFAULT_MATCH).unwrap(),
+                                 None,
+                                 AnsibleContext::default());
+
+        match source.explore().playbooks.iter().find(|p| playbooks::is_sidecar(&source.path.join(p))) {
+            Some(p) => Err(format!("listed sidecar file {} as a playbook", p.to_str().unwrap())),
+            None => Ok(())
+        }
+    }
+
+    #[test]
+    fn content_checked() -> Result<(), String> {
+        let source = Source {
+            check_content: true,
+            ..Source::new(String::from("content"),
+                          get_source_path("content"),
+                          true,
+                          Regex::new(DEFAULT_MATCH).unwrap(),
+                          None,
+                          AnsibleContext::default())
+        };
+
+        let DiscoveryCache { mut playbooks, warnings, .. } = source.explore();
+        playbooks.sort();
+        match (playbooks.iter().filter_map(|p| p.to_str()).collect::<Vec<&str>>().as_slice(), warnings.len()) {
+            (["imports.yml", "site.yml"], 1) => Ok(()),
+            (p, w) => Err(format!("unexpected playbooks {:?} with {} warnings", p, w))
+        }
+    }
+
+    fn patterns(yaml: &str, parameter: &str) -> PathPatterns {
+        PathPatterns::parse(&yaml_rust::YamlLoader::load_from_str(yaml).unwrap()[0], parameter).unwrap()
+    }
+
+    fn expect_exact_playbooks(source: Source, expected: &[&str]) -> Result<(), String> {
+        let mut playbooks = source.explore().playbooks;
+        playbooks.sort();
+        let actual: Vec<&str> = playbooks.iter().filter_map(|p| p.to_str()).collect();
+
+        match actual == expected {
+            true => Ok(()),
+            false => Err(format!("wrong playbook paths returned: {:?}", actual))
+        }
+    }
+
+    #[test]
+    fn playbook_match_relative() -> Result<(), String> {
+        let source = Source::new(String::from("patterns"),
+                                 get_source_path("patterns"),
+                                 true,
+                                 Regex::new(r#"^public/.+\.ya?ml$"#).unwrap(),
+                                 None,
+                                 AnsibleContext::default());
+        expect_exact_playbooks(source, &["public/site.yml", "public/vendor/lib.yml"])
+    }
+
+    #[test]
+    fn include_exclude() -> Result<(), String> {
+        let source = Source {
+            include: patterns("[\"public/\", {regex: \"^top\"}]", "include"),
+            exclude: patterns("[\"vendor/\"]", "exclude"),
+            ..Source::new(String::from("patterns"),
+                          get_source_path("patterns"),
+                          true,
+                          Regex::new(DEFAULT_MATCH).unwrap(),
+                          None,
+                          AnsibleContext::default())
+        };
+        expect_exact_playbooks(source, &["public/site.yml", "top.yml"])
+    }
+
+    #[test]
+    fn ignore_file() -> Result<(), String> {
+        let source = Source::new(String::from("patterns"),
+                                 get_source_path("patterns"),
+                                 true,
+                                 Regex::new(DEFAULT_MATCH).unwrap(),
+                                 None,
+                                 AnsibleContext::default());
+        expect_exact_playbooks(source, &["private/shared.yml", "public/site.yml", "public/vendor/lib.yml", "top.yml"])
+    }
+
+    #[test]
+    fn max_depth() -> Result<(), String> {
+        let source = Source {
+            max_depth: Some(2),
+            ..Source::new(String::from("with_depth"),
+                          get_source_path("with_depth"),
+                          false,
+                          Regex::new(DEFAULT_MATCH).unwrap(),
+                          None,
+                          AnsibleContext::default())
+        };
+        expect_exact_playbooks(source, &["depth1/playbook2.yml", "playbook1.yml"])
+    }
+
+    #[test]
+    fn links_not_followed() -> Result<(), String> {
+        let source = Source::new(String::from("links"),
+                                 get_source_path("links"),
+                                 true,
+                                 Regex::new(DEFAULT_MATCH).unwrap(),
+                                 None,
+                                 AnsibleContext::default());
+        expect_exact_playbooks(source, &["real/playbook.yml"])
+    }
+
+    #[test]
+    fn links_followed() -> Result<(), String> {
+        let source = Source {
+            follow_links: true,
+            ..Source::new(String::from("links"),
+                          get_source_path("links"),
+                          true,
+                          Regex::new(DEFAULT_MATCH).unwrap(),
+                          None,
+                          AnsibleContext::default())
+        };
+
+        let DiscoveryCache { mut playbooks, warnings, .. } = source.explore();
+        playbooks.sort();
+        match (playbooks.iter().filter_map(|p| p.to_str()).collect::<Vec<&str>>().as_slice(), warnings.len()) {
+            (["linked/playbook.yml", "real/playbook.yml"], 2) => Ok(()),
+            (p, _) => Err(format!("unexpected playbooks {:?} with warnings {:?}", p, warnings))
+        }
+    }
+
+    #[test]
+    fn hidden_skipped() -> Result<(), String> {
+        let source = Source {
+            skip_hidden: true,
+            ..Source::new(String::from("hidden"),
+                          get_source_path("hidden"),
+                          true,
+                          Regex::new(DEFAULT_MATCH).unwrap(),
+                          None,
+                          AnsibleContext::default())
+        };
+        expect_exact_playbooks(source, &["playbook.yml", "roles/common/playbook.yml", "visible/playbook.yml"])
+    }
+
+    #[test]
+    fn listed_dirs_skipped() -> Result<(), String> {
+        let source = Source {
+            skip_dirs: vec!["roles".to_string()],
+            ..Source::new(String::from("hidden"),
+                          get_source_path("hidden"),
+                          true,
+                          Regex::new(DEFAULT_MATCH).unwrap(),
+                          None,
+                          AnsibleContext::default())
+        };
+        expect_exact_playbooks(source, &[".hidden/playbook.yml", "playbook.yml", "visible/playbook.yml"])
+    }
+
+    #[test]
+    fn cached_discovery() -> Result<(), String> {
+        let root = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let cache_dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let cache_file = cache_dir.path().join("cached.json");
+        std::fs::write(root.path().join("site.yml"), "").map_err(|e| e.to_string())?;
+
+        let source = Source {
+            cache: Some(cache_file.clone()),
+            ..Source::new(String::from("cached"),
+                          root.path().to_path_buf(),
+                          false,
+                          Regex::new(DEFAULT_MATCH).unwrap(),
+                          None,
+                          AnsibleContext::default())
+        };
+        let walked = source.explore().playbooks;
+
+        /* Tampering with the cache shows whether it is read back */
+        let mut cache = DiscoveryCache::load(&cache_file).ok_or("discovery results were not cached")?;
+        cache.playbooks = vec![PathBuf::from("cached.yml")];
+        cache.save(&cache_file)?;
+        let cached = source.explore().playbooks;
+
+        std::fs::write(root.path().join("other.yml"), "").map_err(|e| e.to_string())?;
+        let refreshed = source.explore().playbooks;
+
+        let names = |p: &[PathBuf]| p.iter().filter_map(|p| p.to_str().map(String::from)).collect::<Vec<String>>();
+        match (names(&walked), names(&cached), names(&refreshed)) {
+            (w, c, r) if w == ["site.yml"] && c == ["cached.yml"] && r == ["other.yml", "site.yml"] => Ok(()),
+            r => Err(format!("unexpected discovery results: {:?}", r))
+        }
+    }
+
     #[test]
     fn pre_provision_none() -> Result<(), String> {
         let source = Source::new(String::from("root_only"),
@@ -258,7 +715,30 @@ mod tests {
                                  None,
                                  AnsibleContext::default());
 
-        source.update().map_err(|e| format!("unexpected error when nothing should have happened: {}", e))
+        source.update().map(|_| ()).map_err(|e| format!("unexpected error when nothing should have happened: {}", e))
+    }
+
+    #[test]
+    fn pre_provision_interval() -> Result<(), String> {
+        let dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let counter = dir.path().join("counter");
+        let source = Source {
+            pre_provision_interval: Some(Duration::from_secs(3600)),
+            state: Some(dir.path().join("state")),
+            ..Source::new(String::from("root_only"),
+                          get_source_path("root_only"),
+                          false,
+                          Regex::new(DEFAULT_MATCH).unwrap(),
+                          Some(format!("echo >> {}", counter.to_str().unwrap())),
+                          AnsibleContext::default())
+        };
+
+        let updates = (source.update()?, source.update()?);
+        let runs = std::fs::read_to_string(&counter).map_err(|e| e.to_string())?.lines().count();
+        match (updates, runs) {
+            ((None, Some(_)), 1) => Ok(()),
+            r => Err(format!("unexpected updates: {:?}", r))
+        }
     }
 
     #[test]
@@ -18,29 +18,64 @@
 //! UI logic
 
 
-use crate::ansible::AnsibleResult;
+use crate::ansible::{AnsibleResult, AnsibleTaskResult};
+use crate::shutdown;
+use crate::audit;
+use crate::menu::{self, Menu, Navigate, Row, Tree};
+use crate::browser::{self, ResultBrowser, ResultFilter};
+use crate::playbooks::PlaybookInfo;
 
 use std::io::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use atty;
 use termion::{clear, color, style, cursor};
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
 use lazy_static::lazy_static;
+use serde_json::{json, Value as Json};
 
 
 pub trait UserInterface: Sync {
     fn intro(&self);
     fn error(&self, message: &str);
+    fn warning(&self, message: &str);
+    fn notice(&self, message: &str);
     fn next_step(&self);
-    fn present_pubkey(&self, username: &str, pubkey: &str);
-    fn prompt_from_vec(&self, message: &str, choices: &Vec<String>) -> usize;
+    fn present_pubkey(&self, username: &str, algorithm: &str, snippet: &str);
+    /// Prompts for one of the choices, or None if the client went back (only offered with `back`)
+    fn prompt_from_vec(&self, message: &str, choices: &[String], back: bool) -> Option<usize>;
+    /// Prompts for a playbook given their relative paths and what could be read from them, as a folder tree with `tree`
+    fn prompt_playbook(&self, message: &str, paths: &[String], infos: &[PlaybookInfo], tree: bool, back: bool) -> Option<usize>;
     fn render_ansible_result(&self, result: &AnsibleResult);
+    fn progress(&self, count: usize, task: &AnsibleTaskResult);
 
-    fn intro_pubkey(&self, username: &str) {
+    fn intro_pubkey(&self, username: &str, algorithm: &str) {
         self.next_step();
-        println!("SetMeUp will be using an ECDSA keypair to authenticate with your machine.");
-        println!("Please make sure user {} has the following public key in their ~/.ssh/authorized_keys file:\n", username);
+        println!("SetMeUp will be using an {} keypair to authenticate with your machine.", algorithm);
+        println!("Please authorise its public key by running the following command as user {} on your machine:\n", username);
     }
 
+    fn run_started(&self, run_id: &str, detached: bool) {
+        match detached {
+            true => println!("This run ({}) will carry on if you get disconnected: reconnect with `attach {}` as a command to follow it again.\n",
+                             run_id, run_id),
+            false => println!("Run ID: {}\n", run_id)
+        }
+    }
+
+    /// Shows the warnings and notices held back for the next screen, when there will be none
+    fn flush_messages(&self) {}
+
+    /// Shows a line of a hook's output as it runs
+    fn hook_output(&self, hook: &str, line: &str) {
+        println!("[{}] {}", hook, line);
+    }
+
+    /// Prints a report after the results, only where the output is not meant for a human at a terminal
+    fn report(&self, _format: &str, _content: &str) {}
+
     fn running(&self) {
         print!("Running Ansible (this may take a while)... ");
         std::io::stdout().flush().ok();
@@ -62,18 +97,98 @@ pub trait UserInterface: Sync {
         buffer
     }
 
-    fn prompt_index_in_range(&self, length: usize) -> usize {
-        let mut index_1 = 0;
-        while index_1 <= 0 || index_1 > length {
-            let index_input = self.prompt(&format!("Select by index (1-{}) :", length));
-            index_1 = index_input.parse::<usize>().unwrap_or(0);
+    /// Prompts for some text without echoing it, unless stdin is not a terminal
+    ///
+    /// None of the setup steps asks for a secret yet, but interfaces must not echo one when they do.
+    #[allow(dead_code)]
+    fn prompt_secret(&self, message: &str) -> String {
+        let mut stdin = std::io::stdin();
+        if !termion::is_tty(&stdin) {
+            return self.prompt(message);
+        }
+
+        print!("{} ", message);
+        let mut stdout = std::io::stdout();
+        stdout.flush().ok();
+        match stdin.read_passwd(&mut stdout) {
+            Ok(Some(s)) => {
+                println!();
+                s
+            },
+            Ok(None) => self.exit_with_error("input closed while waiting for an answer"),
+            Err(e) => self.exit_with_error(&format!("failed to read from stdin: {}", e))
+        }
+    }
+
+    /// Prompts for some text, or None if the client typed < to go back
+    fn prompt_or_back(&self, message: &str) -> Option<String> {
+        match self.prompt(&format!("{} (< to go back)", message)) {
+            s if s == "<" => None,
+            s => Some(s)
+        }
+    }
+
+    /// Lists what is about to happen before asking for confirmation
+    fn summary(&self, title: &str, items: &[(String, String)]) {
+        println!("{}\n", title);
+        let width = items.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+        for (key, value) in items {
+            println!("    {:width$}  {}", key, value, width = width);
+        }
+        println!();
+    }
+
+    /// Waits for the client to acknowledge something, returning false if they declined
+    fn confirm(&self, message: &str) -> bool {
+        !matches!(self.prompt(message).to_lowercase().as_str(), "n" | "no")
+    }
+
+    /// Prompts for an index, 0 meaning back if allowed
+    fn prompt_index_in_range(&self, length: usize, back: bool) -> Option<usize> {
+        let message = match back {
+            true => format!("Select by index (1-{}, 0 to go back) :", length),
+            false => format!("Select by index (1-{}) :", length)
+        };
+
+        loop {
+            match self.prompt(&message).parse::<usize>() {
+                Ok(0) if back => return None,
+                Ok(i) if i >= 1 && i <= length => return Some(i - 1),
+                _ => ()
+            }
         }
-        index_1 - 1
     }
 
     fn exit_with_error(&self, message: &str) -> ! {
         self.error(message);
-        std::process::exit(1);
+        audit::log(&format!("session aborted: {}", message));
+        shutdown::exit(1);
+    }
+}
+
+
+/// Lines of playbook details shown under the TTY menu
+const DETAIL_LINES: usize = 5;
+
+/// Two-level selection over relative paths: a folder, then one of its playbooks
+fn prompt_by_folder(ui: &dyn UserInterface, message: &str, choices: &[String], infos: &[PlaybookInfo], back: bool) -> Option<usize> {
+    let groups = menu::group_by_folder(choices);
+    let folders = groups.iter().map(|(f, p)| format!("{} ({})", f, p.len())).collect::<Vec<String>>();
+
+    loop {
+        let folder = match groups.len() {
+            1 => 0,
+            _ => ui.prompt_from_vec("Here are the folders holding playbooks:", &folders, back)?
+        };
+
+        let indices = &groups[folder].1;
+        ui.next_step();
+        match ui.prompt_from_vec(message, &indices.iter().map(|i| infos[*i].label(menu::file_name(&choices[*i]))).collect::<Vec<String>>(),
+                                 back || groups.len() > 1) {
+            Some(i) => return Some(indices[i]),
+            None if groups.len() == 1 => return None,
+            None => ui.next_step()
+        }
     }
 }
 
@@ -90,25 +205,49 @@ impl UserInterface for BasicInterface {
         println!("/!\\ {}", message);
     }
 
+    fn warning(&self, message: &str) {
+        println!("(!) {}", message);
+    }
+
+    fn notice(&self, message: &str) {
+        println!("{}", message);
+    }
+
     fn next_step(&self) {
         println!();
     }
 
-    fn present_pubkey(&self, username: &str, pubkey: &str) {
-        self.intro_pubkey(username);
-        println!("---\n{}\n---\n", pubkey);
+    fn present_pubkey(&self, username: &str, algorithm: &str, snippet: &str) {
+        self.intro_pubkey(username, algorithm);
+        println!("---\n{}\n---\n", snippet);
     }
 
-    fn prompt_from_vec(&self, message: &str, choices: &Vec<String>) -> usize {
+    fn prompt_from_vec(&self, message: &str, choices: &[String], back: bool) -> Option<usize> {
         println!("{}\n", message);
         choices.iter().enumerate().for_each(|(i, c)| println!("    {}. {}", i + 1, c));
         println!();
 
-        self.prompt_index_in_range(choices.len())
+        self.prompt_index_in_range(choices.len(), back)
+    }
+
+    fn prompt_playbook(&self, message: &str, paths: &[String], infos: &[PlaybookInfo], tree: bool, back: bool) -> Option<usize> {
+        match tree {
+            true => prompt_by_folder(self, message, paths, infos, back),
+            false => self.prompt_from_vec(message, &paths.iter().zip(infos).map(|(p, i)| i.label(p)).collect::<Vec<String>>(), back)
+        }
+    }
+
+    fn report(&self, _format: &str, content: &str) {
+        println!("\n{}", content.trim_end());
+    }
+
+    fn progress(&self, _count: usize, _task: &AnsibleTaskResult) {
+        print!(".");
+        std::io::stdout().flush().ok();
     }
 
     fn render_ansible_result(&self, result: &AnsibleResult) {
-        println!("done!");
+        println!(" done!");
         for task in result {
             println!(
                 "`- [{}]{} {}",
@@ -123,12 +262,263 @@ impl UserInterface for BasicInterface {
 }
 
 
-pub struct TTYInterface;
+pub struct TTYInterface {
+    /// Warnings and notices held until the next prompt, since menus take the whole screen
+    pending: Mutex<Vec<String>>
+}
 
 impl TTYInterface {
     fn clear(&self) {
         print!("{}{}", clear::All, cursor::Goto(1, 1));
     }
+
+    /// Takes the pending warnings and notices
+    fn take_pending(&self) -> Vec<String> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+
+    /// Numbered list, for when the terminal cannot be switched to raw mode
+    fn prompt_numbered(&self, message: &str, choices: &[String], back: bool) -> Option<usize> {
+        for line in self.take_pending() {
+            println!("{}", line);
+        }
+        println!("{}\n", message);
+        for (i, c) in choices.iter().enumerate() {
+            println!("    {}{}{}.{} {}",
+                     style::Bold,
+                     color::Fg(color::Cyan),
+                     i + 1,
+                     style::Reset,
+                     c);
+        }
+        println!();
+
+        self.prompt_index_in_range(choices.len(), back)
+    }
+
+    /// Full-screen menu with arrow-key navigation and fuzzy filtering, browsing a folder tree while the filter is empty
+    ///
+    /// Fails if the terminal cannot be driven, and returns None if the client went back.
+    fn prompt_menu(&self, message: &str, choices: &[String], infos: Option<&[PlaybookInfo]>, tree: bool,
+                   back: bool) -> std::io::Result<Option<usize>> {
+        let mut stdout = std::io::stdout().into_raw_mode()?;
+        let mut menu = Menu::new(choices);
+        let mut tree = match tree {
+            true => Some(Tree::new(choices)),
+            false => None
+        };
+        let mut keys = std::io::stdin().keys();
+        let pending = self.take_pending();
+
+        loop {
+            let header: Vec<String> = pending.iter().cloned().chain(vec![
+                message.to_string(),
+                String::new(),
+                format!("{}Filter:{} {}", style::Bold, style::Reset, menu.filter())
+            ]).collect();
+            let browsing = menu.filter().is_empty() && tree.is_some();
+            let label = |i: usize, path: &str| match infos {
+                Some(infos) => infos[i].label(path),
+                None => path.to_string()
+            };
+
+            /* Details of the playbook under the cursor */
+            let selected = match (&tree, browsing) {
+                (Some(t), true) => match t.rows().get(t.cursor()) {
+                    Some(Row::Playbook { index, .. }) => Some(*index),
+                    _ => None
+                },
+                _ => menu.selection()
+            };
+            let footer: Vec<String> = match infos {
+                Some(infos) => {
+                    let mut details = selected.map(|i| infos[i].details()).unwrap_or_default();
+                    details.resize(DETAIL_LINES, String::new());
+                    std::iter::once(String::new()).chain(details).collect()
+                },
+                None => Vec::new()
+            };
+
+            let (width, page) = Self::page_size(header.len() + footer.len());
+
+            let (lines, cursor, offset, help) = match (&mut tree, browsing) {
+                (Some(t), true) => (
+                    t.rows().iter().map(|r| match r {
+                        Row::Folder { depth, name, count, expanded, .. } =>
+                            format!("{}{} {}/ ({})", "  ".repeat(*depth), if *expanded { "-" } else { "+" }, name, count),
+                        Row::Playbook { index, depth, .. } =>
+                            format!("{}  {}", "  ".repeat(*depth), label(*index, menu::file_name(&choices[*index])))
+                    }).collect::<Vec<String>>(),
+                    t.cursor(), t.scroll(page),
+                    format!("{} playbooks - arrows to move and fold, type to filter, Enter to select{}",
+                            choices.len(), if back { ", Esc to go back" } else { "" })
+                ),
+                _ => (
+                    menu.matches().iter().map(|i| label(*i, &choices[*i])).collect(),
+                    menu.cursor(), menu.scroll(page),
+                    format!("{}/{} - arrows/PgUp/PgDn to move, type to filter, Enter to select, Esc to {}",
+                            menu.matches().len(), menu.len(),
+                            match (menu.filter().is_empty(), back) {
+                                (false, _) => "clear",
+                                (true, true) => "go back",
+                                (true, false) => "clear"
+                            })
+                )
+            };
+            Self::draw_screen(&mut stdout, &header, &lines, Some(cursor), offset, width, page, &footer, &help)?;
+
+            let navigation: &mut dyn Navigate = match (&mut tree, browsing) {
+                (Some(t), true) => t,
+                _ => &mut menu
+            };
+
+            match keys.next() {
+                Some(Ok(Key::Up)) => navigation.move_by(-1),
+                Some(Ok(Key::Down)) => navigation.move_by(1),
+                Some(Ok(Key::PageUp)) => navigation.move_by(-(page as isize)),
+                Some(Ok(Key::PageDown)) => navigation.move_by(page as isize),
+                Some(Ok(Key::Home)) => navigation.home(),
+                Some(Ok(Key::End)) => navigation.end(),
+                Some(Ok(Key::Right)) => if let (Some(t), true) = (&mut tree, browsing) { t.expand() },
+                Some(Ok(Key::Left)) => if let (Some(t), true) = (&mut tree, browsing) { t.collapse() },
+                Some(Ok(Key::Char('\n'))) => {
+                    let selection = match (&mut tree, browsing) {
+                        (Some(t), true) => t.activate(),
+                        _ => menu.selection()
+                    };
+
+                    if let Some(i) = selection {
+                        write!(stdout, "{}{}", clear::All, cursor::Goto(1, 1))?;
+                        return Ok(Some(i))
+                    }
+                },
+                Some(Ok(Key::Ctrl('c'))) | Some(Ok(Key::Ctrl('d'))) | None => {
+                    drop(stdout);
+                    shutdown::interrupt("the client from a menu", true)
+                },
+                Some(Ok(Key::Char(c))) if !c.is_control() => menu.push(c),
+                Some(Ok(Key::Backspace)) => menu.pop(),
+                Some(Ok(Key::Esc)) if back && menu.filter().is_empty() => {
+                    write!(stdout, "{}{}", clear::All, cursor::Goto(1, 1))?;
+                    return Ok(None)
+                },
+                Some(Ok(Key::Esc)) => menu.clear(),
+                Some(Err(e)) => return Err(e),
+                _ => ()
+            }
+        }
+    }
+
+    /// Number of list lines fitting on screen between a header and a footer, with the terminal width
+    fn page_size(header: usize) -> (usize, usize) {
+        let (width, height) = match termion::terminal_size() {
+            Ok((w, h)) if w > 0 && h > 0 => (w, h),
+            _ => (80, 24)
+        };
+        (width as usize, (height as usize).saturating_sub(header + 3).max(1))
+    }
+
+    /// Renders a screen in raw mode: a header, a page of lines from the given offset, a footer and a help line
+    #[allow(clippy::too_many_arguments)]
+    fn draw_screen(out: &mut impl Write, header: &[String], lines: &[String], cursor: Option<usize>, offset: usize,
+                   width: usize, page: usize, footer: &[String], help: &str) -> std::io::Result<()> {
+        write!(out, "{}{}", clear::All, cursor::Goto(1, 1))?;
+        for line in header {
+            write!(out, "{}\r\n", line)?;
+        }
+        write!(out, "\r\n")?;
+
+        for (i, line) in lines.iter().enumerate().skip(offset).take(page) {
+            let line: String = line.chars().take(width.saturating_sub(4)).collect();
+            match Some(i) == cursor {
+                true => write!(out, "{}{}> {}{}\r\n", style::Bold, color::Fg(color::Cyan), line, style::Reset)?,
+                false => write!(out, "  {}\r\n", line)?
+            }
+        }
+
+        write!(out, "{}", cursor::Goto(1, (header.len() + page + 3) as u16))?;
+        for line in footer {
+            write!(out, "{}\r\n", line.chars().take(width).collect::<String>())?;
+        }
+        write!(out, "{}", help)?;
+        out.flush()
+    }
+
+    /// Full-screen browser over the results, with per-task details, until the client quits
+    fn browse_results(&self, result: &AnsibleResult) -> Option<()> {
+        let mut stdout = std::io::stdout().into_raw_mode().ok()?;
+        let mut browser = ResultBrowser::new(result);
+        let mut opened: Option<(&AnsibleTaskResult, Vec<String>, usize)> = None;
+        let mut keys = std::io::stdin().keys();
+
+        loop {
+            match opened.as_mut() {
+                Some((task, lines, offset)) => {
+                    let header = [
+                        format!("{}{}{}", style::Bold, task.name, style::Reset),
+                        format!("{}{}{}",
+                                if task.success { "ok" } else { "failed" },
+                                if task.changed { ", changed" } else { "" },
+                                task.duration.map(|d| format!(", took {}", browser::format_duration(d))).unwrap_or_default())
+                    ];
+                    let (width, page) = Self::page_size(header.len());
+                    *offset = (*offset).min(lines.len().saturating_sub(page));
+                    Self::draw_screen(&mut stdout, &header, lines, None, *offset, width, page,
+                                      &[], "arrows/PgUp/PgDn to scroll, Esc to go back").ok()?;
+
+                    let scroll = match keys.next() {
+                        Some(Ok(Key::Up)) => -1,
+                        Some(Ok(Key::Down)) => 1,
+                        Some(Ok(Key::PageUp)) => -(page as isize),
+                        Some(Ok(Key::PageDown)) => page as isize,
+                        Some(Ok(Key::Esc)) | Some(Ok(Key::Left)) | Some(Ok(Key::Backspace)) | Some(Ok(Key::Char('q'))) => {
+                            opened = None;
+                            continue
+                        },
+                        Some(Ok(Key::Ctrl('c'))) | Some(Ok(Key::Ctrl('d'))) | Some(Err(_)) | None => break,
+                        _ => 0
+                    };
+                    *offset = (*offset as isize + scroll).max(0) as usize;
+                },
+
+                None => {
+                    let header = [
+                        format!("{}{}{}", style::Bold, browser::summary(result), style::Reset),
+                        format!("Showing {} tasks", browser.filter().name())
+                    ];
+                    let (width, page) = Self::page_size(header.len());
+                    let lines: Vec<String> = browser.visible().iter().map(|i| {
+                        let task = &result[*i];
+                        format!("[{}]{} {}", if task.success { "ok" } else { "KO" }, if task.changed { " (change)" } else { "" }, task.name)
+                    }).collect();
+                    let offset = browser.scroll(page);
+                    Self::draw_screen(&mut stdout, &header, &lines, Some(browser.cursor()), offset, width, page,
+                                      &[], "a/f/c to show all/failed/changed tasks, Enter for details, q to quit").ok()?;
+
+                    match keys.next() {
+                        Some(Ok(Key::Up)) => browser.move_by(-1),
+                        Some(Ok(Key::Down)) => browser.move_by(1),
+                        Some(Ok(Key::PageUp)) => browser.move_by(-(page as isize)),
+                        Some(Ok(Key::PageDown)) => browser.move_by(page as isize),
+                        Some(Ok(Key::Home)) => browser.home(),
+                        Some(Ok(Key::End)) => browser.end(),
+                        Some(Ok(Key::Char('a'))) => browser.set_filter(ResultFilter::All),
+                        Some(Ok(Key::Char('f'))) => browser.set_filter(ResultFilter::Failed),
+                        Some(Ok(Key::Char('c'))) => browser.set_filter(ResultFilter::Changed),
+                        Some(Ok(Key::Char('\n'))) | Some(Ok(Key::Right)) => {
+                            opened = browser.selection().map(|t| (t, browser::details(t), 0))
+                        },
+                        Some(Ok(Key::Esc)) | Some(Ok(Key::Char('q'))) | Some(Ok(Key::Ctrl('c'))) | Some(Ok(Key::Ctrl('d')))
+                            | Some(Err(_)) | None => break,
+                        _ => ()
+                    }
+                }
+            }
+        }
+
+        write!(stdout, "{}{}", clear::All, cursor::Goto(1, 1)).ok();
+        Some(())
+    }
 }
 
 impl UserInterface for TTYInterface {
@@ -147,36 +537,64 @@ impl UserInterface for TTYInterface {
                  style::Reset);
     }
 
+    fn warning(&self, message: &str) {
+        self.pending.lock().unwrap().extend(message.lines().map(|l| format!("{}{}{}", color::Fg(color::Yellow), l, style::Reset)));
+    }
+
+    fn notice(&self, message: &str) {
+        self.pending.lock().unwrap().extend(message.lines().map(String::from));
+    }
+
+    fn flush_messages(&self) {
+        for line in self.take_pending() {
+            println!("{}", line);
+        }
+    }
+
     fn next_step(&self) {
         self.clear();
     }
 
-    fn present_pubkey(&self, username: &str, pubkey: &str) {
-        self.intro_pubkey(username);
+    fn present_pubkey(&self, username: &str, algorithm: &str, snippet: &str) {
+        self.intro_pubkey(username, algorithm);
         println!("{}{}{}{}\n",
                  style::Bold,
                  color::Fg(color::Blue),
-                 pubkey,
+                 snippet,
                  style::Reset);
     }
 
-    fn prompt_from_vec(&self, message: &str, choices: &Vec<String>) -> usize {
-        println!("{}\n", message);
-        for (i, c) in choices.iter().enumerate() {
-            println!("    {}{}{}.{} {}",
-                     style::Bold,
-                     color::Fg(color::Cyan),
-                     i + 1,
-                     style::Reset,
-                     c);
+    fn prompt_from_vec(&self, message: &str, choices: &[String], back: bool) -> Option<usize> {
+        match self.prompt_menu(message, choices, None, false, back) {
+            Ok(i) => i,
+            Err(_) => self.prompt_numbered(message, choices, back)
         }
-        println!();
+    }
 
-        self.prompt_index_in_range(choices.len())
+    fn prompt_playbook(&self, message: &str, paths: &[String], infos: &[PlaybookInfo], tree: bool, back: bool) -> Option<usize> {
+        match (self.prompt_menu(message, paths, Some(infos), tree, back), tree) {
+            (Ok(i), _) => i,
+            (Err(_), true) => prompt_by_folder(self, message, paths, infos, back),
+            (Err(_), false) => self.prompt_numbered(message, &paths.iter().zip(infos).map(|(p, i)| i.label(p)).collect::<Vec<String>>(), back)
+        }
+    }
+
+    fn progress(&self, count: usize, task: &AnsibleTaskResult) {
+        print!("\r{}Running Ansible (this may take a while)... {}[{}]{} {}",
+               clear::CurrentLine,
+               style::Bold, count, style::Reset,
+               task.name);
+        std::io::stdout().flush().ok();
     }
 
     fn render_ansible_result(&self, result: &AnsibleResult) {
+        if !result.is_empty() {
+            self.browse_results(result);
+        }
+
+        print!("\r{}Running Ansible (this may take a while)... ", clear::CurrentLine);
         println!("{}{}done!{}", color::Fg(color::Cyan), style::Bold, style::Reset);
+        println!("{}\n", browser::summary(result));
 
         let ok = format!("{}{}✓{}", color::Fg(color::Green), style::Bold, style::Reset);
         let ko = format!("{}{}x{}", color::Fg(color::Red), style::Bold, style::Reset);
@@ -198,10 +616,236 @@ impl UserInterface for TTYInterface {
 }
 
 
+/// Newline-delimited JSON events on stdout, answers read back from stdin, for GUIs and automation
+pub struct JSONInterface {
+    prompt_id: AtomicUsize
+}
+
+/// Version of the JSON event protocol, bumped on incompatible changes
+const JSON_PROTOCOL: u32 = 1;
+
+impl JSONInterface {
+    fn new() -> Self {
+        Self { prompt_id: AtomicUsize::new(0) }
+    }
+
+    fn emit(&self, event: Json) {
+        println!("{}", event);
+        std::io::stdout().flush().ok();
+    }
+
+    fn task(task: &AnsibleTaskResult) -> Json {
+        json!({
+            "name": task.name,
+            "success": task.success,
+            "changed": task.changed,
+            "message": task.message,
+            "duration": task.duration,
+            "details": task.details
+        })
+    }
+
+    /// Emits a prompt event and reads answers until one is valid, as checked and converted by `accept`
+    ///
+    /// With `back`, the client may answer with `"back": true` instead of a value, which returns None.
+    fn ask<T>(&self, kind: &str, message: &str, extra: Json, back: bool, accept: impl Fn(&Json) -> Option<T>) -> Option<T> {
+        let id = self.prompt_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut event = json!({ "event": "prompt", "id": id, "kind": kind, "message": message, "back": back });
+        if let (Some(e), Json::Object(x)) = (event.as_object_mut(), extra) {
+            e.extend(x);
+        }
+        self.emit(event);
+
+        loop {
+            let mut buffer = String::new();
+            match std::io::stdin().read_line(&mut buffer) {
+                Ok(0) => self.exit_with_error("input closed while waiting for an answer"),
+                Ok(_) => (),
+                Err(e) => self.exit_with_error(&format!("failed to read from stdin: {}", e))
+            }
+
+            let answer = serde_json::from_str::<Json>(&buffer).ok()
+                .filter(|a| a["id"].as_u64() == Some(id as u64))
+                .and_then(|a| match a["back"].as_bool() {
+                    Some(true) if back => Some(None),
+                    _ => accept(&a["value"]).map(Some)
+                });
+            match answer {
+                Some(a) => return a,
+                None => self.emit(json!({ "event": "invalid_answer", "id": id, "answer": buffer.trim_end() }))
+            }
+        }
+    }
+}
+
+impl UserInterface for JSONInterface {
+    fn intro(&self) {
+        self.emit(json!({ "event": "hello", "protocol": JSON_PROTOCOL, "version": env!("CARGO_PKG_VERSION") }));
+    }
+
+    fn error(&self, message: &str) {
+        self.emit(json!({ "event": "error", "message": message }));
+    }
+
+    fn warning(&self, message: &str) {
+        self.emit(json!({ "event": "warning", "message": message }));
+    }
+
+    fn notice(&self, message: &str) {
+        self.emit(json!({ "event": "notice", "message": message }));
+    }
+
+    fn next_step(&self) {}
+
+    fn present_pubkey(&self, username: &str, algorithm: &str, snippet: &str) {
+        self.emit(json!({ "event": "pubkey", "username": username, "algorithm": algorithm, "snippet": snippet }));
+    }
+
+    fn prompt_from_vec(&self, message: &str, choices: &[String], back: bool) -> Option<usize> {
+        self.ask("choice", message, json!({ "choices": choices }), back,
+                 |v| v.as_u64().map(|i| i as usize).filter(|i| *i < choices.len()))
+    }
+
+    /// Trees are left to the client to build, from the relative paths
+    fn prompt_playbook(&self, message: &str, paths: &[String], infos: &[PlaybookInfo], _tree: bool, back: bool) -> Option<usize> {
+        let details: Vec<Json> = infos.iter().map(PlaybookInfo::to_json).collect();
+
+        self.ask("choice", message, json!({ "choices": paths, "details": details }), back,
+                 |v| v.as_u64().map(|i| i as usize).filter(|i| *i < paths.len()))
+    }
+
+    fn prompt(&self, message: &str) -> String {
+        self.ask("text", message, json!({}), false, |v| v.as_str().map(String::from)).unwrap_or_default()
+    }
+
+    fn prompt_secret(&self, message: &str) -> String {
+        self.ask("secret", message, json!({}), false, |v| v.as_str().map(String::from)).unwrap_or_default()
+    }
+
+    fn prompt_or_back(&self, message: &str) -> Option<String> {
+        self.ask("text", message, json!({}), true, |v| v.as_str().map(String::from))
+    }
+
+    fn summary(&self, title: &str, items: &[(String, String)]) {
+        self.emit(json!({
+            "event": "summary",
+            "title": title,
+            "items": items.iter().map(|(k, v)| json!({ "name": k, "value": v })).collect::<Vec<Json>>()
+        }));
+    }
+
+    fn confirm(&self, message: &str) -> bool {
+        self.ask("confirm", message, json!({}), true, |v| v.as_bool()).unwrap_or(false)
+    }
+
+    fn run_started(&self, run_id: &str, detached: bool) {
+        self.emit(json!({ "event": "run_started", "run_id": run_id, "detached": detached }));
+    }
+
+    fn running(&self) {
+        self.emit(json!({ "event": "running" }));
+    }
+
+    fn progress(&self, count: usize, task: &AnsibleTaskResult) {
+        self.emit(json!({ "event": "progress", "count": count, "task": Self::task(task) }));
+    }
+
+    fn hook_output(&self, hook: &str, line: &str) {
+        self.emit(json!({ "event": "hook_output", "hook": hook, "line": line }));
+    }
+
+    fn report(&self, format: &str, content: &str) {
+        self.emit(json!({ "event": "report", "format": format, "content": content }));
+    }
+
+    fn render_ansible_result(&self, result: &AnsibleResult) {
+        self.emit(json!({ "event": "result", "tasks": result.iter().map(Self::task).collect::<Vec<Json>>() }));
+    }
+}
+
+
+/// Which interface to use, selected from the command line, the configuration or the environment
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UIMode {
+    Basic,
+    Tty,
+    Json
+}
+
+impl UIMode {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "basic" => Ok(Self::Basic),
+            "tty" => Ok(Self::Tty),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unsupported UI mode {} (expected basic, tty or json)", name))
+        }
+    }
+
+    /// Picks the fancy interface only when talking to a capable terminal both ways
+    fn choose(stdin_tty: bool, stdout_tty: bool, term: Option<&str>, no_color: bool) -> Self {
+        match (stdin_tty && stdout_tty, term, no_color) {
+            (true, Some(t), false) if !t.is_empty() && t != "dumb" => Self::Tty,
+            _ => Self::Basic
+        }
+    }
+
+    pub fn detect() -> Self {
+        Self::choose(atty::is(atty::Stream::Stdin),
+                     atty::is(atty::Stream::Stdout),
+                     std::env::var("TERM").ok().as_deref(),
+                     std::env::var_os("NO_COLOR").is_some())
+    }
+}
+
+lazy_static! {
+    static ref MODE: Mutex<Option<UIMode>> = Mutex::new(None);
+}
+
+/// Forces the interface mode, which only has an effect before the UI is first used
+pub fn select(mode: UIMode) {
+    *MODE.lock().unwrap() = Some(mode);
+}
+
 pub type BoxedInterface = Box<dyn UserInterface>;
 lazy_static! {
-    pub static ref UI: BoxedInterface = match atty::is(atty::Stream::Stdin) {
-        true => Box::new(BasicInterface {}),
-        false => Box::new(TTYInterface {})
+    pub static ref UI: BoxedInterface = match MODE.lock().unwrap().unwrap_or_else(UIMode::detect) {
+        UIMode::Basic => Box::new(BasicInterface {}),
+        UIMode::Tty => Box::new(TTYInterface { pending: Mutex::new(Vec::new()) }),
+        UIMode::Json => Box::new(JSONInterface::new())
     };
 }
+
+
+#[cfg(test)]
+#[cfg(not(tarpaulin_include))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mode_from_name() -> Result<(), String> {
+        match (UIMode::from_name("basic"), UIMode::from_name("tty"), UIMode::from_name("json"), UIMode::from_name("fancy")) {
+            (Ok(UIMode::Basic), Ok(UIMode::Tty), Ok(UIMode::Json), Err(_)) => Ok(()),
+            _ => Err("failed to parse UI mode names".to_string())
+        }
+    }
+
+    #[test]
+    fn mode_detection() -> Result<(), String> {
+        let cases = [
+            ((true, true, Some("xterm-256color"), false), UIMode::Tty),
+            ((true, false, Some("xterm-256color"), false), UIMode::Basic),
+            ((false, true, Some("xterm-256color"), false), UIMode::Basic),
+            ((true, true, None, false), UIMode::Basic),
+            ((true, true, Some("dumb"), false), UIMode::Basic),
+            ((true, true, Some("xterm"), true), UIMode::Basic)
+        ];
+
+        for ((stdin, stdout, term, no_color), expected) in cases.iter() {
+            if UIMode::choose(*stdin, *stdout, *term, *no_color) != *expected {
+                return Err(format!("expected {:?} for {:?}", expected, (stdin, stdout, term, no_color)))
+            }
+        }
+        Ok(())
+    }
+}
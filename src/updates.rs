@@ -0,0 +1,178 @@
+/* Set Me Up, a minimalistic Ansible-based remote provisioning tool
+ * Copyright (C) 2021 Julien JPK (jjpk.me)
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>. */
+
+
+//! Per-source update state: one pre_provision run at a time, and when the last one succeeded
+
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+
+/// What happens to a session when a source fails to update
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UpdatePolicy {
+    /// Ends the session
+    Fail,
+    /// Carries on with the source as it is, warning the client
+    Warn,
+    /// Leaves the source out of the menus
+    Hide
+}
+
+impl UpdatePolicy {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "fail" => Ok(Self::Fail),
+            "warn" => Ok(Self::Warn),
+            "hide" => Ok(Self::Hide),
+            _ => Err(format!("unsupported update failure policy {} (expected fail, warn or hide)", name))
+        }
+    }
+}
+
+
+/// An exclusive lock on a source's state file, released when dropped
+pub struct UpdateLock {
+    file: File
+}
+
+impl UpdateLock {
+    /// Waits for other sessions to be done with the source
+    pub fn acquire(path: &Path) -> Result<Self, String> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| format!("failed to create the source state directory: {}", e))?;
+        }
+
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)
+            .map_err(|e| format!("failed to open the source state file {}: {}", path.display(), e))?;
+
+        loop {
+            match unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } {
+                0 => return Ok(Self { file }),
+                _ => match std::io::Error::last_os_error() {
+                    e if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    e => return Err(format!("failed to lock the source state file {}: {}", path.display(), e))
+                }
+            }
+        }
+    }
+
+    /// Time since the last successful update, if any was recorded
+    pub fn last_update(&mut self) -> Option<Duration> {
+        let mut content = String::new();
+        self.file.seek(SeekFrom::Start(0)).ok()?;
+        self.file.read_to_string(&mut content).ok()?;
+
+        let updated = UNIX_EPOCH + Duration::from_secs(content.trim().parse::<u64>().ok()?);
+        Some(SystemTime::now().duration_since(updated).unwrap_or_default())
+    }
+
+    /// Records a successful update
+    pub fn record_update(&mut self) -> Result<(), String> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.file.set_len(0)
+            .and_then(|_| self.file.seek(SeekFrom::Start(0)))
+            .and_then(|_| self.file.write_all(now.to_string().as_bytes()))
+            .map_err(|e| format!("failed to record the source update: {}", e))
+    }
+}
+
+impl Drop for UpdateLock {
+    fn drop(&mut self) {
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+
+/// Renders the age of something as 30s, 4m, 2h or 3d
+pub fn format_age(age: Duration) -> String {
+    match age.as_secs() {
+        s @ 0..=59 => format!("{}s", s),
+        s @ 60..=3599 => format!("{}m", s / 60),
+        s @ 3600..=86399 => format!("{}h", s / 3600),
+        s => format!("{}d", s / 86400)
+    }
+}
+
+
+#[cfg(test)]
+#[cfg(not(tarpaulin_include))]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn exclusive() -> Result<(), String> {
+        let dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let path = dir.path().join("state/source");
+        let acquired = Arc::new(AtomicBool::new(false));
+
+        let lock = UpdateLock::acquire(&path)?;
+        let waiter = {
+            let (path, acquired) = (path.clone(), acquired.clone());
+            std::thread::spawn(move || {
+                let lock = UpdateLock::acquire(&path);
+                acquired.store(true, Ordering::SeqCst);
+                lock.map(|_| ())
+            })
+        };
+
+        std::thread::sleep(Duration::from_millis(100));
+        let while_locked = acquired.load(Ordering::SeqCst);
+        drop(lock);
+        waiter.join().map_err(|_| "waiting thread panicked".to_string())??;
+
+        match (while_locked, acquired.load(Ordering::SeqCst)) {
+            (false, true) => Ok(()),
+            r => Err(format!("lock was not exclusive: {:?}", r))
+        }
+    }
+
+    #[test]
+    fn update_recorded() -> Result<(), String> {
+        let dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let mut lock = UpdateLock::acquire(&dir.path().join("source"))?;
+        let before = lock.last_update();
+        lock.record_update()?;
+
+        match (before, lock.last_update()) {
+            (None, Some(age)) if age < Duration::from_secs(5) => Ok(()),
+            r => Err(format!("unexpected update ages: {:?}", r))
+        }
+    }
+
+    #[test]
+    fn policy_from_name() -> Result<(), String> {
+        match (UpdatePolicy::from_name("fail"), UpdatePolicy::from_name("warn"), UpdatePolicy::from_name("hide"), UpdatePolicy::from_name("retry")) {
+            (Ok(UpdatePolicy::Fail), Ok(UpdatePolicy::Warn), Ok(UpdatePolicy::Hide), Err(_)) => Ok(()),
+            _ => Err("failed to parse update failure policy names".to_string())
+        }
+    }
+
+    #[test]
+    fn ages() -> Result<(), String> {
+        let ages: Vec<String> = [30, 300, 7200, 259200].iter().map(|s| format_age(Duration::from_secs(*s))).collect();
+        match ages == ["30s", "5m", "2h", "3d"] {
+            true => Ok(()),
+            false => Err(format!("unexpected ages: {:?}", ages))
+        }
+    }
+}